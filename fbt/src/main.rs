@@ -1,19 +1,224 @@
+const HELP: &str = "fbt: folder based testing
+
+Usage: fbt [COMMAND] [OPTIONS] [FILTERS]...
+
+FILTERS select tests whose id contains any of them, ignoring case, a filter
+starting with `!` drops tests whose id contains the rest of it instead.
+
+Commands:
+  run    Run all tests, or the ones matching FILTERS (default)
+  fix    Like run, but overwrite the expected `output` folders, stdout, stderr
+         and combined sections, and stdout and stderr files with actual output
+  list   List tests, or the ones matching FILTERS, without running them
+  check  Validate fbt.p1 and all cmd.p1 files without running anything
+
+Options:
+      --tests-dir <DIR>      Folder containing the tests [default: ./tests]
+      --exclude <PATTERN>    Do not select tests whose id contains PATTERN, same
+                             as `!PATTERN`, can be repeated
+      --exact                FILTERS must match the whole id, case sensitive
+      --regex                FILTERS are regular expressions
+      --tag <TAG>            Only select tests having TAG, can be repeated
+      --exclude-tag <TAG>    Do not select tests having TAG, can be repeated
+      --tags <EXPR>          Only select tests whose tags match EXPR, which can
+                             use `and`, `or`, `not` and parentheses,
+                             eg `slow and not (network or docker)`
+  -x, --fail-fast            Stop at the first failing test
+      --shard <K/N>          Split selected tests in N groups of about the same
+                             duration in the last run, and only select the Kth
+      --repeat <N>           Run every selected test N times, and report the ones
+                             that pass only some of the times as FLAKY
+      --stop-on-flaky        With --repeat, stop at the first FLAKY test
+      --failed               Only select tests that failed in the last run
+      --failed-first         Run tests that failed in the last run first
+  -w, --watch                Rerun tests when they, or files matching `watch`
+                             globs in fbt.p1, change
+      --show-filtered        Also list the tests that were not selected
+      --json                 Print `list` output as JSON
+  -v, --verbose              Show command, environment and timings of each test
+  -h, --help                 Print this help
+  -V, --version              Print version";
+
+#[derive(Debug)]
+enum Command {
+    Run,
+    Fix,
+    List,
+    Check,
+    Help,
+    Version,
+}
+
+#[derive(Debug)]
+struct Cli {
+    command: Command,
+    selection: fbt_lib::Selection,
+    tests_dir: Option<String>,
+    options: fbt_lib::RunOptions,
+    watch: bool,
+    json: bool,
+}
+
 fn main() {
-    if version_asked() {
-        println!("fbt: {}", env!("CARGO_PKG_VERSION"));
+    // skip the first element (name of binary)
+    let mut cli = match parse_args(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("error: {}\n\nRun `fbt --help` for usage.", e);
+            std::process::exit(1)
+        }
+    };
+
+    cli.options.to_fix = match cli.command {
+        Command::Help => {
+            println!("{}", HELP);
+            return;
+        }
+        Command::Version => {
+            println!("fbt: {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Command::List => {
+            if let Some(code) = fbt_lib::main_list(&cli.selection, cli.tests_dir, cli.json) {
+                std::process::exit(code)
+            }
+            return;
+        }
+        Command::Check => {
+            if let Some(code) = fbt_lib::main_check(cli.tests_dir) {
+                std::process::exit(code)
+            }
+            return;
+        }
+        Command::Run => false,
+        Command::Fix => true,
+    };
+
+    if cli.watch {
+        if let Some(code) = fbt_lib::main_watch(&cli.selection, &cli.options, cli.tests_dir) {
+            std::process::exit(code)
+        }
         return;
     }
 
-    let mut args = std::env::args();
-    args.next(); // get rid of first element (name of binary)
-    let to_fix = args.any(|v| v == "--fix" || v == "-f");
-    let args: Vec<_> = args.filter(|v| !v.starts_with('-')).collect();
-
-    if let Some(code) = fbt_lib::main_with_filters(&args, to_fix, None) {
+    if let Some(code) = fbt_lib::main_with_filters(&cli.selection, &cli.options, cli.tests_dir) {
         std::process::exit(code)
     }
 }
 
-fn version_asked() -> bool {
-    std::env::args().any(|e| e == "--version" || e == "-v")
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Cli, String> {
+    let mut cli = Cli {
+        command: Command::Run,
+        selection: Default::default(),
+        tests_dir: None,
+        options: Default::default(),
+        watch: false,
+        json: false,
+    };
+    let mut command = None;
+    // `-f` is `fix`, checked against any sub command once all args are read
+    let mut fix = false;
+
+    while let Some(arg) = args.next() {
+        // `--flag=value` is same as `--flag value`
+        let (flag, mut inline) = match arg.split_once('=') {
+            Some((f, v)) if f.starts_with("--") => (f, Some(v.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || {
+            inline
+                .take()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("`{}` needs a value", flag))
+        };
+        // sub command can only come before any filter
+        let can_be_command = command.is_none() && cli.selection.filters.is_empty();
+
+        match flag {
+            "-h" | "--help" => return Ok(with_command(cli, Command::Help)),
+            "-V" | "--version" => return Ok(with_command(cli, Command::Version)),
+            "-f" | "--fix" => fix = true,
+            "-v" | "--verbose" => cli.options.verbose = true,
+            "-x" | "--fail-fast" => cli.options.fail_fast = true,
+            "-w" | "--watch" => cli.watch = true,
+            "--shard" => cli.selection.shard = Some(parse_shard(value()?.as_str())?),
+            "--repeat" => {
+                let v = value()?;
+                cli.options.repeat = match v.parse() {
+                    Ok(n) if n >= 1 => n,
+                    _ => return Err(format!("invalid repeat count `{}`", v)),
+                };
+            }
+            "--stop-on-flaky" => cli.options.stop_on_flaky = true,
+            "--failed" => cli.selection.failed = true,
+            "--failed-first" => cli.options.failed_first = true,
+            "--show-filtered" => cli.options.show_filtered = true,
+            "--json" => cli.json = true,
+            "--tests-dir" => cli.tests_dir = Some(value()?),
+            "--exclude" => cli.selection.excludes.push(value()?),
+            "--exact" => cli.selection.exact = true,
+            "--regex" => cli.selection.regex = true,
+            "--tag" => cli.selection.tags.push(value()?),
+            "--exclude-tag" => cli.selection.exclude_tags.push(value()?),
+            "--tags" => {
+                let expr = fbt_lib::TagExpr::parse(value()?.as_str())?;
+                // all expressions must match if given more than once
+                cli.selection.tag_expr = Some(match cli.selection.tag_expr.take() {
+                    Some(e) => fbt_lib::TagExpr::And(Box::new(e), Box::new(expr)),
+                    None => expr,
+                });
+            }
+            // used by fbt's own tests, to get output that does not change from
+            // run to run, read by `fbt_lib` directly
+            "--test" => {}
+            v if v.starts_with('-') => return Err(format!("unknown flag `{}`", v)),
+            "run" if can_be_command => command = Some(Command::Run),
+            "fix" if can_be_command => command = Some(Command::Fix),
+            "list" if can_be_command => command = Some(Command::List),
+            "check" if can_be_command => command = Some(Command::Check),
+            v => match v.strip_prefix('!') {
+                Some(v) => cli.selection.excludes.push(v.to_string()),
+                None => cli.selection.filters.push(v.to_string()),
+            },
+        }
+
+        if inline.is_some() {
+            return Err(format!("`{}` does not take a value", flag));
+        }
+    }
+
+    let command = match command {
+        Some(Command::List) | Some(Command::Check) if fix => {
+            return Err("`--fix` can only be used with `run` or `fix`".to_string())
+        }
+        _ if fix => Command::Fix,
+        c => c.unwrap_or(Command::Run),
+    };
+    cli.selection.validate()?;
+    if cli.json && !matches!(command, Command::List) {
+        return Err("`--json` can only be used with `list`".to_string());
+    }
+    if cli.watch && !matches!(command, Command::Run | Command::Fix) {
+        return Err("`--watch` can only be used with `run` or `fix`".to_string());
+    }
+
+    Ok(with_command(cli, command))
+}
+
+// `K/N`, with K between 1 and N
+fn parse_shard(v: &str) -> Result<(usize, usize), String> {
+    match v
+        .split_once('/')
+        .map(|(k, n)| (k.trim().parse(), n.trim().parse()))
+    {
+        Some((Ok(k), Ok(n))) if k >= 1 && k <= n => Ok((k, n)),
+        _ => Err(format!(
+            "invalid shard `{}`, expected K/N with K between 1 and N, eg 1/3",
+            v
+        )),
+    }
+}
+
+fn with_command(cli: Cli, command: Command) -> Cli {
+    Cli { command, ..cli }
 }
@@ -0,0 +1,94 @@
+// stdout and stderr are kept as bytes, as commands can write anything, and are
+// only turned into text when shown
+
+// whitespace at either end removed, unicode whitespace if `v` is utf-8 else
+// ascii whitespace
+pub(crate) fn trim(v: &[u8]) -> &[u8] {
+    if let Ok(s) = std::str::from_utf8(v) {
+        return s.trim().as_bytes();
+    }
+    let start = v
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(v.len());
+    let end = v
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map(|i| i + 1)
+        .unwrap_or(start);
+    &v[start..end]
+}
+
+// every `from` in `v` replaced with `to`
+pub(crate) fn replace(v: &[u8], from: &str, to: &str) -> Vec<u8> {
+    let from = from.as_bytes();
+    if from.is_empty() {
+        return v.to_vec();
+    }
+
+    let mut replaced = Vec::with_capacity(v.len());
+    let mut i = 0;
+    while i < v.len() {
+        if v[i..].starts_with(from) {
+            replaced.extend_from_slice(to.as_bytes());
+            i += from.len();
+        } else {
+            replaced.push(v[i]);
+            i += 1;
+        }
+    }
+    replaced
+}
+
+// utf-8 is shown as is, binary looking output as hex dump and anything else
+// with invalid bytes replaced by `�`
+pub(crate) fn render(v: &[u8]) -> String {
+    match std::str::from_utf8(v) {
+        Ok(s) => s.to_string(),
+        Err(_) if is_binary(v) => hex(v),
+        Err(_) => String::from_utf8_lossy(v).to_string(),
+    }
+}
+
+// `expected` and `found` rendered alike for a diff, as hex dumps if replacing
+// invalid bytes would hide the difference between them
+pub(crate) fn render_pair(expected: &[u8], found: &[u8]) -> (String, String) {
+    if std::str::from_utf8(expected).is_ok() && std::str::from_utf8(found).is_ok() {
+        return (render(expected), render(found));
+    }
+
+    let (e, f) = (
+        String::from_utf8_lossy(expected),
+        String::from_utf8_lossy(found),
+    );
+    if e == f || is_binary(expected) || is_binary(found) {
+        return (hex(expected), hex(found));
+    }
+    (e.to_string(), f.to_string())
+}
+
+fn is_binary(v: &[u8]) -> bool {
+    v.contains(&0)
+}
+
+// 16 bytes per line, `00000010  68 65 6c 6c 6f 0a  |hello.|`
+fn hex(v: &[u8]) -> String {
+    v.chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let bytes: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|b| {
+                    if b.is_ascii_graphic() || *b == b' ' {
+                        *b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!("{:08x}  {:<47}  |{}|", i * 16, bytes.join(" "), ascii)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,137 @@
+// stdin is fed and stdout and stderr are read on their own threads, so a
+// command that writes a lot before it has read all of its stdin does not block
+// on a full pipe while we block on writing to it
+
+pub(crate) struct Captured {
+    pub output: std::process::Output,
+    // `stdout` or `stderr` if it was larger than the limit, only first `limit`
+    // bytes of it are kept
+    pub overflow: Option<&'static str>,
+}
+
+// `combined` is the pipe from `combine()`, if stdout and stderr go to it
+pub(crate) fn wait(
+    mut child: std::process::Child,
+    stdin: Option<Box<dyn std::io::Read + Send>>,
+    combined: Option<std::fs::File>,
+    limit: usize,
+) -> Result<Captured, crate::Failure> {
+    let writer = match (stdin, child.stdin.take()) {
+        (Some(mut stdin), Some(mut pipe)) => Some(std::thread::spawn(move || {
+            // dropping `pipe` at the end closes it, so the command sees EOF
+            std::io::copy(&mut stdin, &mut pipe).map(|_| ())
+        })),
+        _ => None,
+    };
+    let stdout = match combined {
+        Some(pipe) => Some(read(pipe, limit)),
+        None => child.stdout.take().map(|s| read(s, limit)),
+    };
+    let stderr = child.stderr.take().map(|s| read(s, limit));
+
+    let status = child.wait().map_err(|io| crate::Failure::CommandFailed {
+        io,
+        reason: "cant wait",
+    })?;
+
+    if let Some(Ok(Err(io))) = writer.map(|w| w.join()) {
+        // command exited without reading all of its stdin, which is its
+        // business, so only other errors are reported
+        if io.kind() != std::io::ErrorKind::BrokenPipe {
+            return Err(crate::Failure::CommandFailed {
+                io,
+                reason: "cant write to stdin",
+            });
+        }
+    }
+
+    let (stdout, stdout_overflow) = joined(stdout)?;
+    let (stderr, stderr_overflow) = joined(stderr)?;
+
+    Ok(Captured {
+        output: std::process::Output {
+            status,
+            stdout,
+            stderr,
+        },
+        overflow: if stdout_overflow {
+            Some("stdout")
+        } else if stderr_overflow {
+            Some("stderr")
+        } else {
+            None
+        },
+    })
+}
+
+// makes stdout and stderr of `cmd` go to the same pipe, so they can be read
+// in the order they were written, returns the read end of the pipe
+#[cfg(unix)]
+pub(crate) fn combine(cmd: &mut std::process::Command) -> std::io::Result<std::fs::File> {
+    use std::os::unix::io::FromRawFd;
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // so the command does not inherit them, only the copies set as its stdout
+    // and stderr
+    for fd in fds.iter() {
+        unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    let (reader, writer) = unsafe {
+        (
+            std::fs::File::from_raw_fd(fds[0]),
+            std::fs::File::from_raw_fd(fds[1]),
+        )
+    };
+    cmd.stdout(writer.try_clone()?).stderr(writer);
+    Ok(reader)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn combine(_cmd: &mut std::process::Command) -> std::io::Result<std::fs::File> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "combined output is only supported on unix",
+    ))
+}
+
+pub(crate) type Reader = std::thread::JoinHandle<std::io::Result<(Vec<u8>, bool)>>;
+
+// keeps first `limit` bytes of `pipe`, and reads and drops the rest, so the
+// command can finish writing. `true` if something was dropped
+pub(crate) fn read(mut pipe: impl std::io::Read + Send + 'static, limit: usize) -> Reader {
+    std::thread::spawn(move || {
+        let mut kept = vec![];
+        let mut overflow = false;
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = match pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let room = limit.saturating_sub(kept.len());
+            kept.extend_from_slice(&buf[..n.min(room)]);
+            overflow |= n > room;
+        }
+        Ok((kept, overflow))
+    })
+}
+
+pub(crate) fn joined(reader: Option<Reader>) -> Result<(Vec<u8>, bool), crate::Failure> {
+    match reader.map(|r| r.join()) {
+        Some(Ok(Ok(v))) => Ok(v),
+        Some(Ok(Err(io))) => Err(crate::Failure::CommandFailed {
+            io,
+            reason: "cant read output",
+        }),
+        Some(Err(_)) => Err(crate::Failure::CommandFailed {
+            io: std::io::Error::new(std::io::ErrorKind::Other, "reader thread panicked"),
+            reason: "cant read output",
+        }),
+        None => Ok((vec![], false)),
+    }
+}
@@ -0,0 +1,251 @@
+pub fn main_check(folder: Option<String>) -> Option<i32> {
+    use colored::Colorize;
+
+    let problems = match check_all(folder) {
+        Ok(p) => p,
+        Err(e) => {
+            crate::run::print_error(e);
+            return Some(1);
+        }
+    };
+
+    for p in problems.iter() {
+        if p.line_number == 0 {
+            println!("{}: {}", p.file.blue(), p.message);
+        } else {
+            println!("{}:{}: {}", p.file.blue(), p.line_number, p.message);
+        }
+    }
+
+    if problems.is_empty() {
+        println!("{}", "No problems found".green());
+        return None;
+    }
+
+    println!("{}", format!("{} problem(s) found", problems.len()).red());
+    Some(2)
+}
+
+// parses fbt.p1 and every cmd.p1 without running anything, and returns
+// everything that looks wrong in them
+pub fn check_all(folder: Option<String>) -> Result<Vec<crate::Problem>, crate::Error> {
+    let mut problems = vec![];
+    check_suite(
+        &mut problems,
+        std::path::Path::new(crate::run::test_folder(folder).as_str()),
+        &crate::Config::default(),
+    )?;
+    Ok(problems)
+}
+
+fn check_suite(
+    problems: &mut Vec<crate::Problem>,
+    folder: &std::path::Path,
+    parent: &crate::Config,
+) -> Result<(), crate::Error> {
+    let file = folder.join("fbt.p1").to_string_lossy().to_string();
+    let config = match std::fs::read_to_string(file.as_str()) {
+        Ok(content) => {
+            let headers = fbt_headers(content.as_str(), file.as_str());
+            let allow_extensions = headers
+                .iter()
+                .find(|(_, k, _)| k == "allow-extension-headers")
+                .map(|(_, _, v)| v == "true")
+                .unwrap_or(parent.allow_extension_headers);
+            for e in crate::unknown_headers(
+                &headers,
+                file.as_str(),
+                crate::CONFIG_HEADERS,
+                allow_extensions,
+            ) {
+                push_parse_problem(problems, file.as_str(), e);
+            }
+            match crate::Config::parse(content.as_str(), file.as_str(), parent) {
+                Ok(config) => {
+                    check_output(problems, file.as_str(), &headers, &config.output);
+                    config
+                }
+                // tests of the suite are checked as if it did not have an
+                // fbt.p1, so they do not report problems that are not theirs
+                Err(e) => {
+                    push_parse_problem(problems, file.as_str(), e);
+                    crate::Config::inherit(parent)
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => crate::Config::inherit(parent),
+        Err(e) => return Err(crate::Error::CantReadConfig(e)),
+    };
+
+    for (dir, _) in crate::run::child_dirs(folder)? {
+        if crate::run::is_suite(&dir) {
+            check_suite(problems, &dir, &config)?;
+            continue;
+        }
+
+        let file = dir.join("cmd.p1").to_string_lossy().to_string();
+        let content = match std::fs::read_to_string(file.as_str()) {
+            Ok(c) => c,
+            Err(e) => {
+                problems.push(crate::Problem {
+                    file,
+                    line_number: 0,
+                    message: if e.kind() == std::io::ErrorKind::NotFound {
+                        "cmd.p1 is missing".to_string()
+                    } else {
+                        format!("cant read cmd.p1: {:?}", e)
+                    },
+                });
+                continue;
+            }
+        };
+
+        let headers = fbt_headers(content.as_str(), file.as_str());
+        for e in crate::unknown_headers(
+            &headers,
+            file.as_str(),
+            crate::TEST_HEADERS,
+            config.allow_extension_headers,
+        ) {
+            push_parse_problem(problems, file.as_str(), e);
+        }
+
+        let test = match crate::TestConfig::parse(content.as_str(), file.as_str(), &config) {
+            Ok(t) => t,
+            Err(e) => {
+                push_parse_problem(problems, file.as_str(), e);
+                continue;
+            }
+        };
+
+        check_output(problems, file.as_str(), &headers, &test.output);
+        for (key, expected) in [("stdout-file", &test.stdout), ("stderr-file", &test.stderr)] {
+            check_expected_file(problems, file.as_str(), &headers, &dir, key, expected);
+        }
+
+        let has_exit_code =
+            config.exit_code.is_some() || headers.iter().any(|(_, k, _)| k == "exit-code");
+        if test.skip.is_none()
+            && test.stdout.is_none()
+            && test.stderr.is_none()
+            && test.combined.is_none()
+            && !has_exit_code
+            && test.signal.is_none()
+            && !dir.join("output").exists()
+        {
+            problems.push(crate::Problem {
+                file,
+                line_number: 0,
+                message:
+                    "test has no assertions: no stdout, stderr, exit-code, signal or output folder"
+                        .to_string(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// headers of the `-- fbt:` section, empty if the file does not parse, as that
+// gets reported when parsing the config itself
+fn fbt_headers(content: &str, file: &str) -> Vec<(usize, String, String)> {
+    match ftd::p1::parse(content, file) {
+        Ok(sections) => sections
+            .into_iter()
+            .next()
+            .filter(|s| s.name == "fbt")
+            .map(|s| s.header.0)
+            .unwrap_or_default(),
+        Err(_) => vec![],
+    }
+}
+
+fn check_output(
+    problems: &mut Vec<crate::Problem>,
+    file: &str,
+    headers: &[(usize, String, String)],
+    output: &Option<String>,
+) {
+    let output = match output {
+        Some(o) if escapes(o.as_str()) => o,
+        _ => return,
+    };
+    problems.push(crate::Problem {
+        file: file.to_string(),
+        line_number: headers
+            .iter()
+            .find(|(_, k, _)| k == "output")
+            .map(|(l, _, _)| *l)
+            .unwrap_or(0),
+        message: format!("`output: {}` points outside the working directory", output),
+    });
+}
+
+fn check_expected_file(
+    problems: &mut Vec<crate::Problem>,
+    file: &str,
+    headers: &[(usize, String, String)],
+    dir: &std::path::Path,
+    key: &str,
+    expected: &Option<crate::types::Expected>,
+) {
+    let name = match expected.as_ref().and_then(|e| e.file.as_ref()) {
+        Some(f) if !dir.join(f).is_file() => f,
+        _ => return,
+    };
+    problems.push(crate::Problem {
+        file: file.to_string(),
+        line_number: headers
+            .iter()
+            .find(|(_, k, _)| k == key)
+            .map(|(l, _, _)| *l)
+            .unwrap_or(0),
+        message: format!("`{}: {}` is not a file in the test folder", key, name),
+    });
+}
+
+// parsing stops at the first unknown header, which we have already reported
+// along with all other unknown headers, so we skip duplicates
+fn push_parse_problem(problems: &mut Vec<crate::Problem>, file: &str, e: ftd::p1::Error) {
+    let p = parse_problem(file, e);
+    if !problems
+        .iter()
+        .any(|v| v.file == p.file && v.line_number == p.line_number && v.message == p.message)
+    {
+        problems.push(p);
+    }
+}
+
+fn parse_problem(file: &str, e: ftd::p1::Error) -> crate::Problem {
+    match e {
+        ftd::p1::Error::ParseError {
+            message,
+            line_number,
+            ..
+        } => crate::Problem {
+            file: file.to_string(),
+            line_number,
+            message,
+        },
+        e => crate::Problem {
+            file: file.to_string(),
+            line_number: 0,
+            message: format!("{:?}", e),
+        },
+    }
+}
+
+// true if `path`, resolved relative to some folder, ends up outside it
+fn escapes(path: &str) -> bool {
+    let mut depth = 0;
+    for c in std::path::Path::new(path).components() {
+        match c {
+            std::path::Component::Prefix(_) | std::path::Component::RootDir => return true,
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir if depth == 0 => return true,
+            std::path::Component::ParentDir => depth -= 1,
+            std::path::Component::Normal(_) => depth += 1,
+        }
+    }
+    false
+}
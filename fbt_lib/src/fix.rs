@@ -0,0 +1,86 @@
+// `--fix` for stdout, stderr and combined sections of cmd.p1, and for
+// `stdout-file` and `stderr-file`
+
+// `found` replaces what `expected` had, in its file if it has one else in its
+// section of cmd.p1 in `dir`
+pub(crate) fn fix_expected(
+    dir: &std::path::Path,
+    name: &str,
+    expected: &crate::types::Expected,
+    found: &[u8],
+) -> std::io::Result<()> {
+    match expected.file {
+        Some(ref f) => std::fs::write(dir.join(f), found),
+        None => fix_section(
+            &dir.join("cmd.p1"),
+            name,
+            &String::from_utf8_lossy(found),
+            expected.exact,
+        ),
+    }
+}
+
+// replaces the body of `-- name:` section of `cmd_p1` with `value`, keeping
+// the headers of the section and everything around it as is
+fn fix_section(
+    cmd_p1: &std::path::Path,
+    name: &str,
+    value: &str,
+    exact: bool,
+) -> std::io::Result<()> {
+    let content = std::fs::read_to_string(cmd_p1)?;
+    let lines: Vec<&str> = content.lines().collect();
+    let section = format!("-- {}:", name);
+    let start = match lines.iter().position(|l| l.trim_end() == section) {
+        Some(i) => i,
+        None => return Ok(()),
+    };
+
+    // headers of the section end at the first blank line
+    let body_start = lines[start + 1..]
+        .iter()
+        .position(|l| l.trim().is_empty() || l.starts_with("-- "))
+        .map(|i| i + start + 1)
+        .unwrap_or(lines.len());
+    let end = lines[body_start..]
+        .iter()
+        .position(|l| l.starts_with("-- "))
+        .map(|i| i + body_start)
+        .unwrap_or(lines.len());
+
+    let mut fixed: Vec<String> = lines[..body_start].iter().map(|l| l.to_string()).collect();
+    fixed.push("".to_string());
+    fixed.extend(body(value, exact));
+    if end < lines.len() {
+        fixed.push("".to_string());
+        fixed.extend(lines[end..].iter().map(|l| l.to_string()));
+    }
+
+    std::fs::write(cmd_p1, fixed.join("\n") + "\n")
+}
+
+fn body(value: &str, exact: bool) -> Vec<String> {
+    if exact {
+        if value.is_empty() {
+            return vec!["\"\"".to_string()];
+        }
+        // one quoted string per line, so whitespace at either end survives
+        return value
+            .split_inclusive('\n')
+            .map(|l| serde_json::to_string(l).unwrap_or_default())
+            .collect();
+    }
+
+    // lines that would start a new section are escaped
+    value
+        .trim()
+        .lines()
+        .map(|l| {
+            if l.starts_with("--") {
+                format!("\\{}", l)
+            } else {
+                l.to_string()
+            }
+        })
+        .collect()
+}
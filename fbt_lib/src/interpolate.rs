@@ -0,0 +1,179 @@
+// `${VAR}` and `${VAR:-default}` in headers, env values, stdin, commands and
+// build. an undefined variable is an error. `$${` is a literal `${`, any other
+// `${..}` form, like `${#VAR}`, and `$VAR` are left as they are, which is how
+// a command refers to its own shell variables. values put in a command are
+// quoted so the shell sees them as they are, whatever quotes they are in
+
+pub(crate) struct Vars<'a> {
+    // `-- env:` sections, and variables set by fbt, which override them
+    pub env: &'a std::collections::HashMap<String, String>,
+    // if variables of the environment fbt was launched in are visible, they
+    // are not for tests with `clear-env`
+    pub inherit: bool,
+}
+
+impl<'a> Vars<'a> {
+    pub fn expand(&self, v: &str) -> Result<String, String> {
+        self.expand_with(v, &mut vec![], None)
+    }
+
+    // `v` is a line for `sh -c`
+    pub fn expand_shell(&self, v: &str) -> Result<String, String> {
+        self.expand_with(v, &mut vec![], Some(&mut Quoting::default()))
+    }
+
+    // value of `env` variable `key`, which may refer to other variables
+    pub fn expand_env(&self, key: &str) -> Result<Option<String>, String> {
+        self.get(key, &mut vec![])
+    }
+
+    // `seen` are the env variables being expanded, a variable that refers to
+    // itself, eg `PATH=${PATH}:bin`, gets the value from the parent
+    // environment
+    fn get(&self, name: &str, seen: &mut Vec<String>) -> Result<Option<String>, String> {
+        if let Some(v) = self
+            .env
+            .get(name)
+            .filter(|_| !seen.iter().any(|s| s == name))
+        {
+            seen.push(name.to_string());
+            let v = self.expand_with(v, seen, None);
+            seen.pop();
+            return v.map(Some);
+        }
+        if name == "FBT_CWD" {
+            return Ok(std::env::current_dir()
+                .map(|v| v.to_string_lossy().to_string())
+                .ok());
+        }
+        if !self.inherit {
+            return Ok(None);
+        }
+        Ok(std::env::var(name).ok())
+    }
+
+    // `shell` tracks the quotes of the command `v` is, if it is one
+    fn expand_with(
+        &self,
+        v: &str,
+        seen: &mut Vec<String>,
+        mut shell: Option<&mut Quoting>,
+    ) -> Result<String, String> {
+        let mut expanded = String::new();
+        let mut rest = v;
+        while let Some(i) = rest.find("${") {
+            if rest[..i].ends_with('$') {
+                push(&mut expanded, &rest[..i - 1], &mut shell);
+                push(&mut expanded, "${", &mut shell);
+                rest = &rest[i + 2..];
+                continue;
+            }
+
+            let inner = &rest[i + 2..];
+            let end = match closing(inner) {
+                Some(e) => e,
+                None => break,
+            };
+            push(&mut expanded, &rest[..i], &mut shell);
+            let (name, default) = match inner[..end].split_once(":-") {
+                Some((n, d)) if is_name(n) => (n, Some(d)),
+                _ if is_name(&inner[..end]) => (&inner[..end], None),
+                _ => {
+                    push(&mut expanded, &rest[i..i + 2 + end + 1], &mut shell);
+                    rest = &inner[end + 1..];
+                    continue;
+                }
+            };
+
+            // like the shell, default is used if the variable is unset or empty
+            let value = match (self.get(name, seen)?, default) {
+                (Some(v), Some(_)) if !v.is_empty() => v,
+                (Some(v), None) => v,
+                (_, Some(d)) => self.expand_with(d, seen, None)?,
+                (None, None) => return Err(format!("undefined variable `{}`", name)),
+            };
+            match shell {
+                Some(ref q) => expanded.push_str(q.quote(&value).as_str()),
+                None => expanded.push_str(value.as_str()),
+            }
+            rest = &inner[end + 1..];
+        }
+        // also what is left after a `${` that is never closed
+        push(&mut expanded, rest, &mut shell);
+        Ok(expanded)
+    }
+}
+
+// `text` is as written in `v`, so it can open or close quotes
+fn push(expanded: &mut String, text: &str, shell: &mut Option<&mut Quoting>) {
+    if let Some(q) = shell {
+        q.scan(text);
+    }
+    expanded.push_str(text);
+}
+
+// quotes open at some point of a `sh` command line
+#[derive(Default)]
+pub(crate) struct Quoting {
+    single: bool,
+    double: bool,
+    escaped: bool,
+}
+
+impl Quoting {
+    fn scan(&mut self, text: &str) {
+        for c in text.chars() {
+            if self.escaped {
+                self.escaped = false;
+                continue;
+            }
+            match c {
+                '\\' if !self.single => self.escaped = true,
+                '\'' if !self.double => self.single = !self.single,
+                '"' if !self.single => self.double = !self.double,
+                _ => {}
+            }
+        }
+    }
+
+    // `v` as a single word, or part of one, in the current quotes
+    fn quote(&self, v: &str) -> String {
+        if self.single {
+            return v.replace('\'', "'\\''");
+        }
+        if self.double {
+            return v
+                .chars()
+                .flat_map(|c| match c {
+                    '\\' | '"' | '$' | '`' => vec!['\\', c],
+                    _ => vec![c],
+                })
+                .collect();
+        }
+        let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:=@%+,".contains(c);
+        if !v.is_empty() && v.chars().all(plain) {
+            return v.to_string();
+        }
+        format!("'{}'", v.replace('\'', "'\\''"))
+    }
+}
+
+// index of `}` that closes `${`, defaults can have `${..}` of their own
+fn closing(v: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in v.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn is_name(v: &str) -> bool {
+    let mut chars = v.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -0,0 +1,108 @@
+// results of every run are kept in `.fbt/last-run.json` next to the tests
+// folder, so the next run can select or prioritise tests that failed
+
+pub fn read_last_run(test_folder: &str) -> crate::LastRun {
+    let value: serde_json::Value = match std::fs::read_to_string(path(test_folder))
+        .ok()
+        .and_then(|v| serde_json::from_str(v.as_str()).ok())
+    {
+        Some(v) => v,
+        None => return Default::default(),
+    };
+
+    crate::LastRun {
+        tests: value
+            .get("tests")
+            .and_then(|v| v.as_object())
+            .map(|tests| {
+                tests
+                    .iter()
+                    .filter_map(|(id, t)| {
+                        let duration = t
+                            .get("duration")
+                            .and_then(|d| d.as_f64())
+                            .filter(|d| *d >= 0.0)
+                            .map(std::time::Duration::from_secs_f64);
+                        Some((
+                            id.to_string(),
+                            crate::LastResult {
+                                result: serde_json::from_value(t.get("result")?.clone()).ok()?,
+                                duration,
+                            },
+                        ))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+// tests that were not run this time keep their result from earlier runs. the
+// file is read again before writing, so shards that share it, each writing
+// when it is done, combine their results by id. a `sharded` run keeps the
+// durations as they were, as the next shard to run splits the tests by them
+pub fn write_last_run(
+    test_folder: &str,
+    last_run: crate::LastRun,
+    cases: &[crate::Case],
+    sharded: bool,
+) {
+    let mut last_run = {
+        let mut current = read_last_run(test_folder);
+        for (id, t) in last_run.tests {
+            current.tests.entry(id).or_insert(t);
+        }
+        current
+    };
+    for case in cases.iter() {
+        let result = match case.result {
+            // passing only after a retry is kept visible, so `--failed` runs it
+            crate::Status::Passed if !case.retried.is_empty() => crate::LastStatus::Flaky,
+            crate::Status::Passed => crate::LastStatus::Passed,
+            crate::Status::Skipped { .. } => crate::LastStatus::Skipped,
+            crate::Status::Fixed => crate::LastStatus::Fixed,
+            crate::Status::Failed(_) => crate::LastStatus::Failed,
+            crate::Status::Flaky(_) => crate::LastStatus::Flaky,
+            crate::Status::Filtered | crate::Status::NotRun => continue,
+        };
+        let duration = if sharded {
+            last_run.tests.get(&case.id).and_then(|t| t.duration)
+        } else {
+            Some(case.duration)
+        };
+        last_run
+            .tests
+            .insert(case.id.to_string(), crate::LastResult { result, duration });
+    }
+
+    let tests: serde_json::Map<_, _> = last_run
+        .tests
+        .iter()
+        .map(|(id, t)| {
+            let mut v = serde_json::json!({ "result": t.result });
+            if let Some(d) = t.duration {
+                v["duration"] = d.as_secs_f64().into();
+            }
+            (id.to_string(), v)
+        })
+        .collect();
+
+    // failing to save results should not fail the run itself
+    let path = path(test_folder);
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let _ = std::fs::write(
+        path,
+        serde_json::to_string_pretty(&serde_json::json!({ "tests": tests })).unwrap_or_default()
+            + "\n",
+    );
+}
+
+pub(crate) fn path(test_folder: &str) -> std::path::PathBuf {
+    std::path::Path::new(test_folder)
+        .parent()
+        .unwrap_or_else(|| std::path::Path::new("."))
+        .join(".fbt")
+        .join("last-run.json")
+}
@@ -1,8 +1,23 @@
+mod bytes;
+mod capture;
+mod check;
 mod copy_dir;
 mod dir_diff;
+mod fix;
+mod interpolate;
+mod last_run;
+mod list;
 mod run;
+mod signal;
+mod tags;
 mod types;
+mod watch;
 
+pub use check::{check_all, main_check};
 pub use dir_diff::{DirDiff, DirDiffError};
+pub use last_run::{read_last_run, write_last_run};
+pub use list::{list_all, main_list};
 pub use run::{main, main_with_filters, main_with_test_folder, test_all};
+pub use tags::TagExpr;
 pub use types::*;
+pub use watch::main_watch;
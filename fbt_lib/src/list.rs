@@ -0,0 +1,88 @@
+pub fn main_list(selection: &crate::Selection, folder: Option<String>, json: bool) -> Option<i32> {
+    use colored::Colorize;
+
+    let tests = match list_all(selection, folder) {
+        Ok(t) => t,
+        Err(e) => {
+            crate::run::print_error(e);
+            return Some(1);
+        }
+    };
+
+    if json {
+        let tests: Vec<_> = tests
+            .iter()
+            .map(|t| match &t.result {
+                Ok(info) => serde_json::json!({
+                    "id": t.id,
+                    "cmd": info.cmd,
+                    "skip": info.skip,
+                    "tags": info.tags,
+                    "description": info.description,
+                }),
+                Err(e) => serde_json::json!({
+                    "id": t.id,
+                    "error": format!("{:?}", e),
+                }),
+            })
+            .collect();
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&tests).expect("json values are always serializable")
+        );
+        return None;
+    }
+
+    for test in tests.iter() {
+        match &test.result {
+            Ok(info) => {
+                let tags = if info.tags.is_empty() {
+                    "".to_string()
+                } else {
+                    format!(" [{}]", info.tags.join(", ")).magenta().to_string()
+                };
+                match info.skip {
+                    Some(ref reason) => println!(
+                        "{}: {}{} ({}: {})",
+                        test.id.blue(),
+                        info.cmd,
+                        tags,
+                        "SKIPPED".yellow(),
+                        reason
+                    ),
+                    None => println!("{}: {}{}", test.id.blue(), info.cmd, tags),
+                }
+                if let Some(ref description) = info.description {
+                    println!("    {}", description);
+                }
+            }
+            Err(e) => println!("{}: {} ({:?})", test.id.blue(), "ERROR".red(), e),
+        }
+    }
+
+    None
+}
+
+pub fn list_all(
+    selection: &crate::Selection,
+    folder: Option<String>,
+) -> Result<Vec<crate::ListedTest>, crate::Error> {
+    let folder = crate::run::test_folder(folder);
+    let (_, tests) = crate::run::discover(folder.as_str())?;
+    let last_run = crate::read_last_run(folder.as_str());
+
+    Ok(crate::run::select_all(selection, &last_run, tests)
+        .into_iter()
+        .filter_map(|(t, config)| {
+            config.map(|config| crate::ListedTest {
+                result: config.map(|c| crate::TestInfo {
+                    cmd: c.cmd,
+                    skip: c.skip,
+                    tags: c.tags,
+                    description: c.description,
+                }),
+                id: t.id,
+            })
+        })
+        .collect())
+}
@@ -1,41 +1,32 @@
 pub fn main() -> Option<i32> {
-    main_with_filters(&[], false, None)
+    main_with_filters(&Default::default(), &Default::default(), None)
 }
 
 pub fn main_with_test_folder(folder: &str) -> Option<i32> {
-    main_with_filters(&[], false, Some(folder.to_string()))
+    main_with_filters(
+        &Default::default(),
+        &Default::default(),
+        Some(folder.to_string()),
+    )
 }
 
-pub fn main_with_filters(filters: &[String], to_fix: bool, folder: Option<String>) -> Option<i32> {
+pub fn main_with_filters(
+    selection: &crate::Selection,
+    options: &crate::RunOptions,
+    folder: Option<String>,
+) -> Option<i32> {
     use colored::Colorize;
 
-    let cases = match test_all(filters, to_fix, folder) {
+    // read before the run records its own results
+    let nothing_failed = selection.failed && {
+        let last_run = crate::read_last_run(test_folder(folder.clone()).as_str());
+        !last_run.tests.keys().any(|id| last_run.failed(id))
+    };
+
+    let cases = match test_all(selection, options, folder) {
         Ok(tr) => tr,
-        Err(crate::Error::TestsFolderMissing) => {
-            eprintln!("{}", "Tests folder is missing".red());
-            return Some(1);
-        }
-        Err(crate::Error::TestsFolderNotReadable(e)) => {
-            eprintln!("{}", format!("Tests folder is unreadable: {:?}", e).red());
-            return Some(1);
-        }
-        Err(crate::Error::CantReadConfig(e)) => {
-            eprintln!("{}", format!("Cant read config file: {:?}", e).red());
-            return Some(1);
-        }
-        Err(crate::Error::InvalidConfig(e)) => {
-            eprintln!("{}", format!("Cant parse config file: {:?}", e).red());
-            return Some(1);
-        }
-        Err(crate::Error::BuildFailedToLaunch(e)) => {
-            eprintln!(
-                "{}",
-                format!("Build command failed to launch: {:?}", e).red()
-            );
-            return Some(1);
-        }
-        Err(crate::Error::BuildFailed(e)) => {
-            eprintln!("{}", format!("Build failed: {:?}", e).red());
+        Err(e) => {
+            print_error(e);
             return Some(1);
         }
     };
@@ -48,271 +39,876 @@ pub fn main_with_filters(filters: &[String], to_fix: bool, folder: Option<String
             format!(" in {}", format!("{:?}", &case.duration).yellow())
         };
 
+        if case.result.is_failed() {
+            any_failed = true;
+        }
+
         match &case.result {
-            Ok(status) => {
-                if *status {
-                    println!("{}: {}{}", case.id.blue(), "PASSED".green(), duration);
-                } else {
-                    println!("{}: {}", case.id.blue(), "SKIPPED".magenta(),);
+            crate::Status::Passed if !case.retried.is_empty() => {
+                println!(
+                    "{}: {}{}",
+                    case.id.blue(),
+                    "PASSED AFTER RETRY".yellow(),
+                    duration
+                );
+            }
+            crate::Status::Passed => {
+                println!("{}: {}{}", case.id.blue(), "PASSED".green(), duration);
+            }
+            crate::Status::Filtered => {
+                if options.show_filtered {
+                    println!("{}: {}", case.id.blue(), "FILTERED".magenta());
                 }
             }
-            Err(crate::Failure::Skipped { reason }) => {
+            crate::Status::Skipped { reason } => {
                 println!("{}: {} ({})", case.id.blue(), "SKIPPED".yellow(), reason,);
             }
-            Err(crate::Failure::UnexpectedStatusCode { expected, output }) => {
-                any_failed = true;
-                println!(
-                    "{}: {}{} (exit code mismatch, expected={}, found={:?})",
-                    case.id.blue(),
-                    "FAILED".red(),
+            crate::Status::NotRun => {
+                println!("{}: {}", case.id.blue(), "NOT RUN".yellow());
+            }
+            crate::Status::Fixed => {
+                println!("{}: {}{}", case.id.blue(), "FIXED".purple(), duration,);
+            }
+            crate::Status::Failed(f) => {
+                print_failure(case.id.as_str(), "FAILED".red(), duration.as_str(), f)
+            }
+            crate::Status::Flaky(f) => {
+                print_failure(case.id.as_str(), "FLAKY".red(), duration.as_str(), f)
+            }
+        }
+
+        // failures are kept so flaky tests do not go unnoticed
+        for (i, f) in case.retried.iter().enumerate() {
+            let label = format!("attempt {} of {} FAILED", i + 1, case.retried.len() + 1);
+            print_failure(case.id.as_str(), label.red(), "", f);
+        }
+
+        if let Some(ref r) = case.repeat {
+            let durations = if is_test() {
+                "".to_string()
+            } else {
+                format!(
+                    ", min {}, median {}, max {}",
+                    format!("{:?}", r.min).yellow(),
+                    format!("{:?}", r.median).yellow(),
+                    format!("{:?}", r.max).yellow()
+                )
+            };
+            println!("  {} of {} runs passed{}", r.passed, r.runs, durations);
+        }
+
+        if let (true, Some(trace)) = (options.verbose, &case.trace) {
+            print_trace(trace);
+        }
+    }
+
+    // when some tests are not selected, show which ones were, so a filter with
+    // a typo does not go unnoticed
+    let selected: Vec<&str> = cases
+        .iter()
+        .filter(|c| !matches!(c.result, crate::Status::Filtered))
+        .map(|c| c.id.as_str())
+        .collect();
+    if selected.len() != cases.len() {
+        // with more shards than tests some shards are empty, which is fine
+        if let (true, Some((k, n))) = (selected.is_empty(), selection.shard) {
+            println!("No tests in shard {}/{}", k, n);
+            return None;
+        }
+        if selected.is_empty() && nothing_failed {
+            println!("No tests failed in the last run");
+            return None;
+        }
+        if selected.is_empty() {
+            eprintln!(
+                "{}",
+                format!("No tests selected, all {} tests were filtered", cases.len()).red()
+            );
+            return Some(1);
+        }
+        println!(
+            "selected {} of {} tests: {}",
+            selected.len(),
+            cases.len(),
+            selected.join(", ")
+        );
+    }
+
+    if any_failed {
+        return Some(2);
+    }
+
+    None
+}
+
+// `label` is FAILED, or FLAKY for a case that only failed some of the times
+fn print_failure(
+    id: &str,
+    label: colored::ColoredString,
+    duration: &str,
+    failure: &crate::Failure,
+) {
+    use colored::Colorize;
+
+    match failure {
+        crate::Failure::UnexpectedStatusCode { expected, output } => {
+            println!(
+                "{}: {}{} (exit code mismatch, expected {}, found {})",
+                id.blue(),
+                label,
+                duration,
+                expected,
+                output.exit_code.unwrap_or_default()
+            );
+            println!("stdout:\n{}\n", crate::bytes::render(&output.stdout));
+            println!("stderr:\n{}\n", crate::bytes::render(&output.stderr));
+        }
+        crate::Failure::UnexpectedSignal { expected, output } => {
+            match expected {
+                Some(s) => println!(
+                    "{}: {}{} (signal mismatch, expected={}, found={})",
+                    id.blue(),
+                    label,
                     duration,
-                    expected,
-                    output.exit_code
-                );
-                println!("stdout:\n{}\n", &output.stdout);
-                println!("stderr:\n{}\n", &output.stderr);
+                    crate::signal::signal_name(*s)
+                        .map(|n| n.to_string())
+                        .unwrap_or_else(|| s.to_string()),
+                    output.status()
+                ),
+                None => println!(
+                    "{}: {}{} (killed by {})",
+                    id.blue(),
+                    label,
+                    duration,
+                    output.status()
+                ),
             }
-            Err(crate::Failure::StdoutMismatch { expected, output }) => {
-                any_failed = true;
+            println!("stdout:\n{}\n", crate::bytes::render(&output.stdout));
+            println!("stderr:\n{}\n", crate::bytes::render(&output.stderr));
+        }
+        crate::Failure::CantInterpolate { field, message } => {
+            println!(
+                "{}: {}{} (cant interpolate {}: {})",
+                id.blue(),
+                label,
+                duration,
+                field,
+                message
+            );
+        }
+        crate::Failure::StdinCmdFailed { status, stderr } => {
+            println!(
+                "{}: {}{} (stdin-cmd failed, {})",
+                id.blue(),
+                label,
+                duration,
+                status
+            );
+            println!(
+                "stderr:\n{}\n",
+                crate::bytes::render(crate::bytes::trim(stderr))
+            );
+        }
+        crate::Failure::CaptureOverflow { stream, limit } => {
+            println!(
+                "{}: {}{} ({} is larger than capture-limit of {} bytes)",
+                id.blue(),
+                label,
+                duration,
+                stream,
+                limit
+            );
+        }
+        crate::Failure::PassedAfterRetry => {
+            println!(
+                "{}: {}{} (passed only after a retry)",
+                id.blue(),
+                label,
+                duration
+            );
+        }
+        crate::Failure::StdoutMismatch { expected, output } => {
+            println!("{}: {}{} (stdout mismatch)", id.blue(), label, duration,);
+            let (expected, found) = crate::bytes::render_pair(expected, &output.stdout);
+            println!("stdout:\n\n{}\n", found);
+            println!(
+                "diff:\n\n{}\n",
+                diffy::create_patch((expected + "\n").as_str(), (found + "\n").as_str())
+            );
+        }
+        crate::Failure::CombinedMismatch { expected, output } => {
+            println!("{}: {}{} (combined mismatch)", id.blue(), label, duration,);
+            let (expected, found) = crate::bytes::render_pair(expected, &output.stdout);
+            println!("combined:\n\n{}\n", found);
+            println!(
+                "diff:\n\n{}\n",
+                diffy::create_patch((expected + "\n").as_str(), (found + "\n").as_str())
+            );
+        }
+        crate::Failure::StderrMismatch { expected, output } => {
+            println!("{}: {}{} (stderr mismatch)", id.blue(), label, duration,);
+            let (expected, found) = crate::bytes::render_pair(expected, &output.stderr);
+            println!("stderr:\n\n{}\n", found);
+            println!(
+                "diff:\n\n{}\n",
+                diffy::create_patch((expected + "\n").as_str(), (found + "\n").as_str())
+            );
+        }
+        crate::Failure::OutputMismatch { diff } => match diff {
+            crate::DirDiff::ContentMismatch {
+                found,
+                expected,
+                file,
+            } => {
                 println!(
-                    "{}: {}{} (stdout mismatch)",
-                    case.id.blue(),
-                    "FAILED".red(),
+                    "{}: {}{} (output content mismatch: {})",
+                    id.blue(),
+                    label,
                     duration,
+                    file.to_str().unwrap_or("cant-read-filename"),
                 );
-                println!("stdout:\n\n{}\n", &output.stdout);
+                println!("found:\n\n{}\n", found.as_str());
                 println!(
                     "diff:\n\n{}\n",
                     diffy::create_patch(
                         (expected.to_owned() + "\n").as_str(),
-                        (output.stdout.clone() + "\n").as_str()
+                        (found.to_owned() + "\n").as_str()
                     )
                 );
             }
-            Err(crate::Failure::StderrMismatch { expected, output }) => {
-                any_failed = true;
+            crate::DirDiff::UnexpectedFileFound { found } => {
                 println!(
-                    "{}: {}{} (stderr mismatch)",
-                    case.id.blue(),
-                    "FAILED".red(),
+                    "{}: {}{} (extra file found: {})",
+                    id.blue(),
+                    label,
                     duration,
+                    found.to_str().unwrap_or("cant-read-filename"),
                 );
-                println!("stderr:\n\n{}\n", &output.stderr);
-                println!(
-                    "diff:\n\n{}\n",
-                    diffy::create_patch(
-                        (expected.to_owned() + "\n").as_str(),
-                        (output.stderr.clone() + "\n").as_str()
-                    )
-                );
-            }
-            Err(crate::Failure::OutputMismatch { diff }) => {
-                any_failed = true;
-                match diff {
-                    crate::DirDiff::ContentMismatch {
-                        found,
-                        expected,
-                        file,
-                    } => {
-                        println!(
-                            "{}: {}{} (output content mismatch: {})",
-                            case.id.blue(),
-                            "FAILED".red(),
-                            duration,
-                            file.to_str().unwrap_or("cant-read-filename"),
-                        );
-                        println!("found:\n\n{}\n", found.as_str());
-                        println!(
-                            "diff:\n\n{}\n",
-                            diffy::create_patch(
-                                (expected.to_owned() + "\n").as_str(),
-                                (found.to_owned() + "\n").as_str()
-                            )
-                        );
-                    }
-                    crate::DirDiff::UnexpectedFileFound { found } => {
-                        println!(
-                            "{}: {}{} (extra file found: {})",
-                            case.id.blue(),
-                            "FAILED".red(),
-                            duration,
-                            found.to_str().unwrap_or("cant-read-filename"),
-                        );
-                    }
-                    _ => {
-                        println!(
-                            "{}: {}{} (output mismatch: {:?})",
-                            case.id.blue(),
-                            "FAILED".red(),
-                            duration,
-                            diff
-                        );
-                    }
-                }
-            }
-            Err(crate::Failure::FixMismatch) => {
-                println!("{}: {}{}", case.id.blue(), "FIXED".purple(), duration,);
             }
-            Err(e) => {
-                any_failed = true;
+            _ => {
                 println!(
-                    "{}: {}{} ({:?})",
-                    case.id.blue(),
-                    "FAILED".red(),
+                    "{}: {}{} (output mismatch: {:?})",
+                    id.blue(),
+                    label,
                     duration,
-                    e
+                    diff
                 );
             }
+        },
+        e => {
+            println!("{}: {}{} ({:?})", id.blue(), label, duration, e);
         }
     }
+}
 
-    if any_failed {
-        return Some(2);
-    }
+pub(crate) fn print_error(e: crate::Error) {
+    use colored::Colorize;
 
-    None
+    match e {
+        crate::Error::TestsFolderMissing => {
+            eprintln!("{}", "Tests folder is missing".red());
+        }
+        crate::Error::TestsFolderNotReadable(e) => {
+            eprintln!("{}", format!("Tests folder is unreadable: {:?}", e).red());
+        }
+        crate::Error::CantReadConfig(e) => {
+            eprintln!("{}", format!("Cant read config file: {:?}", e).red());
+        }
+        crate::Error::InvalidConfig(e) => {
+            eprintln!("{}", format!("Cant parse config file: {:?}", e).red());
+        }
+        crate::Error::BuildFailedToLaunch(e) => {
+            eprintln!(
+                "{}",
+                format!("Build command failed to launch: {:?}", e).red()
+            );
+        }
+        crate::Error::BuildFailed(e) => {
+            eprintln!("{}", format!("Build failed: {:?}", e).red());
+        }
+        crate::Error::CantInterpolateBuild(e) => {
+            eprintln!("{}", format!("Cant interpolate build: {}", e).red());
+        }
+    }
 }
 
 pub fn test_all(
-    filters: &[String],
-    to_fix: bool,
+    selection: &crate::Selection,
+    options: &crate::RunOptions,
     folder: Option<String>,
 ) -> Result<Vec<crate::Case>, crate::Error> {
     let mut results = vec![];
+    let mut failed = false;
 
-    let test_folder = folder
-        .map(|v| v.trim_end_matches('/').to_string())
-        .unwrap_or_else(|| "./tests".to_string());
-    let config = match std::fs::read_to_string(format!("{}/fbt.p1", test_folder).as_str()) {
-        Ok(v) => match crate::Config::parse(v.as_str(), format!("{}/fbt.p1", test_folder).as_str())
-        {
-            Ok(config) => {
-                if let Some(ref b) = config.build {
-                    match if cfg!(target_os = "windows") {
-                        let mut c = std::process::Command::new("cmd");
-                        c.args(&["/C", b.as_str()]);
-                        c
-                    } else {
-                        let mut c = std::process::Command::new("sh");
-                        c.args(&["-c", b.as_str()]);
-                        c
-                    }
-                    .output()
-                    {
-                        Ok(v) => {
-                            if !v.status.success() {
-                                return Err(crate::Error::BuildFailed(v));
-                            }
-                        }
-                        Err(e) => return Err(crate::Error::BuildFailedToLaunch(e)),
-                    }
-                }
-                config
+    let folder = test_folder(folder);
+    let (root, tests) = discover(folder.as_str())?;
+    let last_run = crate::read_last_run(folder.as_str());
+    let mut tests = select_all(selection, &last_run, tests);
+    if options.failed_first {
+        // sort is stable, so tests keep their order otherwise
+        tests.sort_by_key(|(t, _)| !last_run.failed(t.id.as_str()));
+    }
+    // folders of suites whose build has already run
+    let mut built = vec![];
+    let env = fbt_env(folder.as_str(), options.to_fix);
+    build_suite(&root, &env, &mut built)?;
+
+    for (test, config) in tests {
+        // see if filter matches, else continue
+        let start = std::time::Instant::now();
+
+        let config = match config {
+            Some(c) => c,
+            None => {
+                results.push(crate::Case {
+                    id: test.id,
+                    result: crate::Status::Filtered,
+                    duration: std::time::Instant::now().duration_since(start),
+                    trace: None,
+                    repeat: None,
+                    retried: vec![],
+                });
+                continue;
             }
-            Err(e) => return Err(crate::Error::InvalidConfig(e)),
-        },
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => crate::Config::default(),
-        Err(e) => return Err(crate::Error::CantReadConfig(e)),
-    };
+        };
 
-    let dirs = {
-        let mut dirs: Vec<_> = match {
-            match std::fs::read_dir(test_folder.as_str()) {
-                Ok(dirs) => dirs,
-                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                    return Err(crate::Error::TestsFolderMissing)
-                }
-                Err(e) => return Err(crate::Error::TestsFolderNotReadable(e)),
+        if failed {
+            results.push(crate::Case {
+                id: test.id,
+                result: crate::Status::NotRun,
+                duration: std::time::Instant::now().duration_since(start),
+                trace: None,
+                repeat: None,
+                retried: vec![],
+            });
+            continue;
+        }
+
+        build_suite(&test.suite, &env, &mut built)?;
+        let config = config.map(|mut c| {
+            for (k, v) in env.iter() {
+                c.set_env(k, v.clone());
             }
+            c.set_env("FBT_TEST_ID", test.id.clone());
+            c.set_env("FBT_TEST_DIR", absolute(&test.dir));
+            c
+        });
+        let case = if options.repeat > 1 {
+            repeat_one(config, test.dir, test.id, options)
+        } else {
+            test_one(config, test.dir, test.id, start, options.to_fix)
+        };
+        failed = (options.fail_fast && case.result.is_failed())
+            || (options.stop_on_flaky && matches!(case.result, crate::Status::Flaky(_)));
+        results.push(case);
+    }
+
+    crate::write_last_run(
+        folder.as_str(),
+        last_run,
+        &results,
+        selection.shard.is_some(),
+    );
+    Ok(results)
+}
+
+// variables every test, and build, gets besides `FBT_CWD`
+fn fbt_env(folder: &str, to_fix: bool) -> Vec<(&'static str, String)> {
+    let mut env = vec![("FBT_TESTS_DIR", absolute(std::path::Path::new(folder)))];
+    if to_fix {
+        env.push(("FBT_FIX", "1".to_string()));
+    }
+    env
+}
+
+fn absolute(path: &std::path::Path) -> String {
+    std::fs::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .to_string()
+}
+
+pub(crate) fn test_folder(folder: Option<String>) -> String {
+    folder
+        .map(|v| v.trim_end_matches('/').to_string())
+        .unwrap_or_else(|| "./tests".to_string())
+}
+
+pub(crate) fn read_config(
+    folder: &std::path::Path,
+    parent: &crate::Config,
+) -> Result<crate::Config, crate::Error> {
+    let path = folder.join("fbt.p1");
+    match std::fs::read_to_string(&path) {
+        Ok(v) => crate::Config::parse(v.as_str(), path.to_string_lossy().as_ref(), parent)
+            .map_err(crate::Error::InvalidConfig),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(crate::Config::default()),
+        Err(e) => Err(crate::Error::CantReadConfig(e)),
+    }
+}
+
+// runs the build of `suite`, after builds of all its parents, unless they have
+// already been run
+fn build_suite(
+    suite: &crate::Suite,
+    env: &[(&'static str, String)],
+    built: &mut Vec<std::path::PathBuf>,
+) -> Result<(), crate::Error> {
+    if let Some(ref parent) = suite.parent {
+        build_suite(parent, env, built)?;
+    }
+    if built.contains(&suite.folder) {
+        return Ok(());
+    }
+    built.push(suite.folder.clone());
+
+    match suite.config.build {
+        Some(ref b) => build(b.as_str(), env),
+        None => Ok(()),
+    }
+}
+
+fn build(b: &str, env: &[(&'static str, String)]) -> Result<(), crate::Error> {
+    let vars: std::collections::HashMap<_, _> = env
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.clone()))
+        .collect();
+    let b = crate::interpolate::Vars {
+        env: &vars,
+        inherit: true,
+    }
+    .expand_shell(b)
+    .map_err(crate::Error::CantInterpolateBuild)?;
+    let b = b.as_str();
+    match if cfg!(target_os = "windows") {
+        let mut c = std::process::Command::new("cmd");
+        c.args(&["/C", b]);
+        c
+    } else {
+        let mut c = std::process::Command::new("sh");
+        c.args(&["-c", b]);
+        c
+    }
+    .env(
+        "FBT_CWD",
+        std::env::current_dir()
+            .map(|v| v.to_string_lossy().to_string())
+            .unwrap_or_else(|_| "".into()),
+    )
+    .envs(env.iter().map(|(k, v)| (k, v)))
+    .output()
+    {
+        Ok(v) => {
+            if !v.status.success() {
+                return Err(crate::Error::BuildFailed(v));
+            }
+        }
+        Err(e) => return Err(crate::Error::BuildFailedToLaunch(e)),
+    }
+
+    Ok(())
+}
+
+// the top level suite, and all tests in it and in suites nested in it, sorted
+// by their ids
+pub(crate) fn discover(
+    test_folder: &str,
+) -> Result<(std::rc::Rc<crate::Suite>, Vec<crate::TestDir>), crate::Error> {
+    let folder = std::path::PathBuf::from(test_folder);
+    let root = std::rc::Rc::new(crate::Suite {
+        config: read_config(&folder, &crate::Config::default())?,
+        folder,
+        parent: None,
+    });
+
+    let mut tests = vec![];
+    discover_suite(&root, "", &mut tests)?;
+    Ok((root, tests))
+}
+
+fn discover_suite(
+    suite: &std::rc::Rc<crate::Suite>,
+    prefix: &str,
+    tests: &mut Vec<crate::TestDir>,
+) -> Result<(), crate::Error> {
+    for (dir, name) in child_dirs(&suite.folder)? {
+        let id = format!("{}{}", prefix, name);
+        if is_suite(&dir) {
+            let sub = std::rc::Rc::new(crate::Suite {
+                config: read_config(&dir, &suite.config)?,
+                folder: dir,
+                parent: Some(suite.clone()),
+            });
+            discover_suite(&sub, format!("{}/", id).as_str(), tests)?;
+            continue;
         }
-        .map(|res| res.map(|e| e.path()))
-        .collect::<Result<Vec<_>, std::io::Error>>()
-        {
+        tests.push(crate::TestDir {
+            dir,
+            id,
+            suite: suite.clone(),
+        });
+    }
+
+    Ok(())
+}
+
+pub(crate) fn is_suite(dir: &std::path::Path) -> bool {
+    !dir.join("cmd.p1").exists() && dir.join("fbt.p1").exists()
+}
+
+// all folders in `folder`, sorted by name, along with their names
+pub(crate) fn child_dirs(
+    folder: &std::path::Path,
+) -> Result<Vec<(std::path::PathBuf, String)>, crate::Error> {
+    let mut dirs: Vec<_> = match {
+        match std::fs::read_dir(folder) {
             Ok(dirs) => dirs,
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
                 return Err(crate::Error::TestsFolderMissing)
             }
             Err(e) => return Err(crate::Error::TestsFolderNotReadable(e)),
-        };
-        dirs.sort();
-        dirs
+        }
+    }
+    .map(|res| res.map(|e| e.path()))
+    .collect::<Result<Vec<_>, std::io::Error>>()
+    {
+        Ok(dirs) => dirs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(crate::Error::TestsFolderMissing)
+        }
+        Err(e) => return Err(crate::Error::TestsFolderNotReadable(e)),
     };
+    dirs.sort();
 
-    for dir in dirs {
-        if !dir.is_dir() {
-            continue;
-        }
+    Ok(dirs
+        .into_iter()
+        .filter(|dir| dir.is_dir())
+        .map(|dir| {
+            let name = dir
+                .file_name()
+                .map(|v| v.to_str())
+                .unwrap_or(None)
+                .map(ToString::to_string)
+                .unwrap_or_else(|| format!("{:?}", dir.file_name()));
+            (dir, name)
+        })
+        .filter(|(_, name)| !name.starts_with('.'))
+        .collect())
+}
 
-        let dir_name = dir
-            .file_name()
-            .map(|v| v.to_str())
-            .unwrap_or(None)
-            .unwrap_or("");
+// every test along with its config if it is selected, with `--shard` applied
+// to the selected ones
+pub(crate) fn select_all(
+    selection: &crate::Selection,
+    last_run: &crate::LastRun,
+    tests: Vec<crate::TestDir>,
+) -> Vec<(
+    crate::TestDir,
+    Option<Result<crate::TestConfig, crate::Failure>>,
+)> {
+    let mut tests: Vec<_> = tests
+        .into_iter()
+        .map(|t| {
+            let config = select(selection, last_run, &t);
+            (t, config)
+        })
+        .collect();
 
-        if dir_name.starts_with('.') {
-            continue;
+    if let Some((k, n)) = selection.shard {
+        let selected: Vec<_> = tests
+            .iter()
+            .filter(|(_, c)| c.is_some())
+            .map(|(t, _)| t.id.as_str())
+            .collect();
+        let shards = shards(&selected, last_run, n);
+        let mut shards = shards.into_iter();
+        for (_, config) in tests.iter_mut().filter(|(_, c)| c.is_some()) {
+            if shards.next() != Some(k - 1) {
+                *config = None;
+            }
         }
+    }
 
-        // see if filter matches, else continue
-        let start = std::time::Instant::now();
+    tests
+}
 
-        let filter_is_not_empty = !filters.is_empty();
-        let something_matches = !filters
-            .iter()
-            .any(|v| dir_name.to_lowercase().contains(&v.to_lowercase()));
+// splits `ids` into `n` groups whose total durations in the last run are as
+// close as possible, and returns the group of each id. tests without a
+// recorded duration are assumed to take the average time. every machine
+// must see the same last run for the split to be the same on all of them
+fn shards(ids: &[&str], last_run: &crate::LastRun, n: usize) -> Vec<usize> {
+    let known: Vec<_> = ids
+        .iter()
+        .filter_map(|id| last_run.tests.get(*id).and_then(|t| t.duration))
+        .collect();
+    let average = if known.is_empty() {
+        std::time::Duration::from_secs(1)
+    } else {
+        known.iter().sum::<std::time::Duration>() / known.len() as u32
+    };
+    let durations: Vec<_> = ids
+        .iter()
+        .map(|id| {
+            last_run
+                .tests
+                .get(*id)
+                .and_then(|t| t.duration)
+                .unwrap_or(average)
+        })
+        .collect();
 
-        if filter_is_not_empty && something_matches {
-            results.push(crate::Case {
-                id: dir_name.to_string(),
-                result: Ok(false),
-                duration: std::time::Instant::now().duration_since(start),
-            });
-            continue;
-        }
+    // longest tests first, each to the group with least total so far
+    let mut order: Vec<usize> = (0..ids.len()).collect();
+    order.sort_by(|a, b| durations[*b].cmp(&durations[*a]).then(a.cmp(b)));
+    let mut totals = vec![std::time::Duration::default(); n];
+    let mut shards = vec![0; ids.len()];
+    for i in order {
+        let shard = (0..n).min_by_key(|s| (totals[*s], *s)).unwrap_or(0);
+        totals[shard] += durations[i];
+        shards[i] = shard;
+    }
+    shards
+}
 
-        results.push(test_one(&config, dir, start, to_fix));
+// config of `test` if it is selected, tests whose cmd.p1 can not be read are
+// selected if their id matches, so the problem gets reported
+fn select(
+    selection: &crate::Selection,
+    last_run: &crate::LastRun,
+    test: &crate::TestDir,
+) -> Option<Result<crate::TestConfig, crate::Failure>> {
+    if !selection.matches_id(test.id.as_str())
+        || (selection.failed && !last_run.failed(test.id.as_str()))
+    {
+        return None;
     }
 
-    Ok(results)
+    match read_test_config(&test.suite.config, &test.dir, test.id.as_str()) {
+        Ok(c) if !selection.matches_tags(&c.tags) => None,
+        c => Some(c),
+    }
 }
 
-fn test_one(
+pub(crate) fn read_test_config(
     global: &crate::Config,
+    entry: &std::path::Path,
+    id: &str,
+) -> Result<crate::TestConfig, crate::Failure> {
+    match std::fs::read_to_string(entry.join("cmd.p1")) {
+        Ok(c) => crate::TestConfig::parse(c.as_str(), format!("{}/cmd.p1", id).as_str(), global)
+            .map_err(|e| crate::Failure::CmdFileInvalid { error: e }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(crate::Failure::CmdFileMissing),
+        Err(e) => Err(crate::Failure::CantReadCmdFile { error: e }),
+    }
+}
+
+fn test_one(
+    config: Result<crate::TestConfig, crate::Failure>,
     entry: std::path::PathBuf,
+    id: String,
     start: std::time::Instant,
     to_fix: bool,
 ) -> crate::Case {
-    use std::{borrow::BorrowMut, io::Write};
-
-    let id = entry
-        .file_name()
-        .map(|v| v.to_str())
-        .unwrap_or(None)
-        .map(ToString::to_string)
-        .unwrap_or_else(|| format!("{:?}", entry.file_name()));
-
-    let id_ = id.as_str();
-    let err = |e: crate::Failure| crate::Case {
-        id: id_.to_string(),
-        result: Err(e),
+    let mut trace = None;
+    let mut retried = vec![];
+    let result = match config {
+        // every attempt runs in its own temp dir, with a fresh copy of input
+        Ok(config) => loop {
+            match run_one(config.clone(), &entry, to_fix, &mut trace) {
+                crate::Status::Failed(f) if retried.len() < config.retries => retried.push(f),
+                r => break r,
+            }
+        },
+        Err(e) => crate::Status::Failed(e),
+    };
+
+    crate::Case {
+        id,
+        result,
         duration: std::time::Instant::now().duration_since(start),
+        trace,
+        repeat: None,
+        retried,
+    }
+}
+
+// runs a test `options.repeat` times, each in a fresh temp dir, it is flaky if
+// it passes some of the times and fails the others
+fn repeat_one(
+    config: Result<crate::TestConfig, crate::Failure>,
+    entry: std::path::PathBuf,
+    id: String,
+    options: &crate::RunOptions,
+) -> crate::Case {
+    let config = match config {
+        Ok(c) => c,
+        Err(e) => return test_one(Err(e), entry, id, std::time::Instant::now(), options.to_fix),
     };
 
-    let config = match std::fs::read_to_string(entry.join("cmd.p1")) {
-        Ok(c) => {
-            match crate::TestConfig::parse(c.as_str(), format!("{}/cmd.p1", id).as_str(), global) {
-                Ok(c) => c,
-                Err(e) => return err(crate::Failure::CmdFileInvalid { error: e }),
-            }
+    let mut runs = vec![];
+    for _ in 0..options.repeat {
+        let case = test_one(
+            Ok(config.clone()),
+            entry.clone(),
+            id.clone(),
+            std::time::Instant::now(),
+            options.to_fix,
+        );
+        if let crate::Status::Skipped { .. } = case.result {
+            return case;
         }
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-            return err(crate::Failure::CmdFileMissing)
+        runs.push(case);
+
+        if options.stop_on_flaky && is_flaky(&runs) {
+            break;
         }
-        Err(e) => return err(crate::Failure::CantReadCmdFile { error: e }),
+    }
+
+    let passed = runs.iter().filter(|c| !c.result.is_failed()).count();
+    let mut durations: Vec<_> = runs.iter().map(|c| c.duration).collect();
+    durations.sort();
+    let repeat = crate::Repeat {
+        runs: runs.len(),
+        passed,
+        min: durations[0],
+        median: durations[durations.len() / 2],
+        max: durations[durations.len() - 1],
     };
 
+    // the first failed run is shown, else the first that needed a retry
+    let shown = runs
+        .iter()
+        .position(|c| c.result.is_failed())
+        .or_else(|| runs.iter().position(|c| !c.retried.is_empty()))
+        .unwrap_or(0);
+    let flaky = is_flaky(&runs);
+    let run = runs.swap_remove(shown);
+    crate::Case {
+        id,
+        result: match run.result {
+            crate::Status::Failed(f) if flaky => crate::Status::Flaky(f),
+            crate::Status::Passed if flaky => {
+                crate::Status::Flaky(crate::Failure::PassedAfterRetry)
+            }
+            r => r,
+        },
+        duration: repeat.median,
+        trace: run.trace,
+        repeat: Some(repeat),
+        retried: run.retried,
+    }
+}
+
+// some runs passed and some did not, or some passed only after a retry
+fn is_flaky(runs: &[crate::Case]) -> bool {
+    let passed = runs.iter().filter(|c| !c.result.is_failed());
+    let retried = passed.clone().any(|c| !c.retried.is_empty());
+    let passed = passed.count();
+    retried || (passed != 0 && passed != runs.len())
+}
+
+type Feed = (Option<Box<dyn std::io::Read + Send>>, Option<StdinCmd>);
+
+// a running `stdin-cmd`, its stderr is read on a thread so it can not block
+// on writing it
+struct StdinCmd {
+    child: std::process::Child,
+    stderr: crate::capture::Reader,
+}
+
+impl StdinCmd {
+    // `stdin-cmd` killed by SIGPIPE is fine, the command did not read all of
+    // it. the shell exits with 128 + SIGPIPE when it is its child that got
+    // killed
+    fn wait(mut self) -> Result<(), crate::Failure> {
+        let status = self
+            .child
+            .wait()
+            .map_err(|io| crate::Failure::CommandFailed {
+                io,
+                reason: "cant wait for stdin-cmd",
+            })?;
+        let (stderr, _) = crate::capture::joined(Some(self.stderr))?;
+
+        #[cfg(unix)]
+        let pipe = std::os::unix::process::ExitStatusExt::signal(&status) == Some(libc::SIGPIPE)
+            || status.code() == Some(128 + libc::SIGPIPE);
+        #[cfg(not(unix))]
+        let pipe = false;
+
+        if status.success() || pipe {
+            Ok(())
+        } else {
+            Err(crate::Failure::StdinCmdFailed { status, stderr })
+        }
+    }
+
+    // when the command could not be started
+    fn kill(mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = self.stderr.join();
+    }
+}
+
+// bytes to write to stdin of the command, or `stdin-cmd` spawned with its
+// stdout piped, to be made the stdin of the command. `limit` applies to stderr
+// of `stdin-cmd`
+fn open_stdin(
+    config: &crate::TestConfig,
+    entry: &std::path::Path,
+    dir: &std::path::Path,
+    limit: usize,
+) -> Result<Feed, crate::Failure> {
+    match config.stdin {
+        None => Ok((None, None)),
+        Some(crate::types::Stdin::Text(ref v)) => {
+            Ok((Some(Box::new(std::io::Cursor::new(v.clone()))), None))
+        }
+        Some(crate::types::Stdin::File(ref f)) => {
+            let path = if dir.join(f).exists() {
+                dir.join(f)
+            } else {
+                entry.join(f)
+            };
+            match std::fs::File::open(path) {
+                Ok(file) => Ok((Some(Box::new(file)), None)),
+                Err(io) => Err(crate::Failure::Other { io }),
+            }
+        }
+        Some(crate::types::Stdin::Cmd(ref c)) => {
+            let mut cmd = config.shell(c.as_str());
+            cmd.current_dir(dir)
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped());
+            match cmd.spawn() {
+                Ok(mut child) => {
+                    let stderr = match child.stderr.take() {
+                        Some(s) => crate::capture::read(s, limit),
+                        None => crate::capture::read(std::io::empty(), limit),
+                    };
+                    Ok((None, Some(StdinCmd { child, stderr })))
+                }
+                Err(io) => Err(crate::Failure::CommandFailed {
+                    io,
+                    reason: "cant fork stdin-cmd",
+                }),
+            }
+        }
+    }
+}
+
+fn run_one(
+    mut config: crate::TestConfig,
+    entry: &std::path::Path,
+    to_fix: bool,
+    trace: &mut Option<crate::Trace>,
+) -> crate::Status {
+    let err = crate::Status::Failed;
+
     if let Some(reason) = config.skip {
-        return err(crate::Failure::Skipped { reason });
+        return crate::Status::Skipped { reason };
     };
 
     let fbt = {
@@ -335,6 +931,7 @@ fn test_one(
 
     // if input folder exists, we copy it into tmp and run our command from
     // inside that folder, else we run it from tmp
+    let copy_start = std::time::Instant::now();
     let dir = if input.exists() {
         let dir = fbt.join("input");
         if !input.is_dir() {
@@ -343,15 +940,48 @@ fn test_one(
         if let Err(e) = crate::copy_dir::copy_dir_all(&input, &dir) {
             return err(crate::Failure::Other { io: e });
         }
+        config.set_env("FBT_INPUT", absolute(&dir));
         dir
     } else {
-        fbt
+        fbt.clone()
+    };
+    let copy = copy_start.elapsed();
+    config.set_env("FBT_TMP", absolute(&fbt));
+    config.set_env("FBT_WORKDIR", absolute(&dir));
+    if let Err(f) = config.interpolate() {
+        return err(f);
+    }
+
+    let mut cmd = config.cmd();
+    cmd.current_dir(&dir);
+    let combined = match config.combined {
+        Some(_) => match crate::capture::combine(&mut cmd) {
+            Ok(pipe) => Some(pipe),
+            Err(io) => {
+                return err(crate::Failure::CommandFailed {
+                    io,
+                    reason: "cant combine stdout and stderr",
+                })
+            }
+        },
+        None => None,
+    };
+    let (stdin, mut stdin_cmd) = match open_stdin(&config, entry, &dir, config.capture_limit) {
+        Ok(v) => v,
+        Err(f) => return err(f),
     };
+    if let Some(out) = stdin_cmd.as_mut().and_then(|c| c.child.stdout.take()) {
+        cmd.stdin(out);
+    }
+    let trace = trace.insert(crate::Trace::new(&config, &cmd, &dir, copy));
 
-    // eprintln!("executing '{}' in {:?}", &config.cmd, &dir);
-    let mut child = match config.cmd().current_dir(&dir).spawn() {
+    let run_start = std::time::Instant::now();
+    let child = match cmd.spawn() {
         Ok(c) => c,
         Err(io) => {
+            if let Some(c) = stdin_cmd {
+                c.kill();
+            }
             return err(crate::Failure::CommandFailed {
                 io,
                 reason: "cant fork process",
@@ -359,55 +989,127 @@ fn test_one(
         }
     };
 
-    if let (Some(ref stdin), Some(cstdin)) = (config.stdin, &mut child.stdin) {
-        if let Err(io) = cstdin.borrow_mut().write_all(stdin.as_bytes()) {
-            return err(crate::Failure::CommandFailed {
-                io,
-                reason: "cant write to stdin",
-            });
+    // `cmd` holds the write end of `combined` pipe, which has to be closed for
+    // us to see the end of output
+    drop(cmd);
+    let captured = crate::capture::wait(child, stdin, combined, config.capture_limit);
+    if let Some(c) = stdin_cmd {
+        if let Err(f) = c.wait() {
+            return err(f);
         }
     }
-
-    let output = match child.wait_with_output() {
-        Ok(o) => o,
-        Err(io) => {
-            return err(crate::Failure::CommandFailed {
-                io,
-                reason: "cant wait",
+    trace.run = run_start.elapsed();
+    let output = match captured {
+        Ok(crate::capture::Captured {
+            overflow: Some(stream),
+            ..
+        }) => {
+            return err(crate::Failure::CaptureOverflow {
+                stream,
+                limit: config.capture_limit,
             })
         }
+        Ok(c) => c.output,
+        Err(e) => return err(e),
     };
 
-    let output = match crate::Output::try_from(&output) {
-        Ok(o) => o.replace(dir.to_string_lossy().to_string()),
-        Err(reason) => {
-            return err(crate::Failure::CantReadOutput { reason, output });
+    let compare_start = std::time::Instant::now();
+    let result = compare(config, entry, dir, output, to_fix);
+    trace.compare = compare_start.elapsed();
+
+    result
+}
+
+fn compare(
+    config: crate::TestConfig,
+    entry: &std::path::Path,
+    dir: std::path::PathBuf,
+    raw: std::process::Output,
+    to_fix: bool,
+) -> crate::Status {
+    let err = crate::Status::Failed;
+
+    // `exact` keeps whitespace around stdout and stderr, for sections that
+    // compare them exactly
+    let cwd = dir.to_string_lossy().to_string();
+    let (output, exact) = match (
+        crate::Output::new(&raw, true),
+        crate::Output::new(&raw, false),
+    ) {
+        (Ok(o), Ok(e)) => (o.replace(cwd.clone()), e.replace(cwd)),
+        (Err(reason), _) | (_, Err(reason)) => {
+            return err(crate::Failure::CantReadOutput {
+                reason,
+                output: raw,
+            });
         }
     };
 
-    if output.exit_code != config.exit_code {
-        return err(crate::Failure::UnexpectedStatusCode {
-            expected: config.exit_code,
-            output,
-        });
+    match (config.signal, output.signal) {
+        (None, None) => {
+            if !output
+                .exit_code
+                .map(|c| config.exit_code.matches(c))
+                .unwrap_or(false)
+            {
+                return err(crate::Failure::UnexpectedStatusCode {
+                    expected: config.exit_code,
+                    output,
+                });
+            }
+        }
+        (Some(expected), Some(found)) if expected == found => {}
+        (expected, _) => {
+            return err(crate::Failure::UnexpectedSignal { expected, output });
+        }
     }
 
-    if let Some(ref stdout) = config.stdout {
-        if output.stdout != stdout.trim() {
-            return err(crate::Failure::StdoutMismatch {
-                output,
-                expected: stdout.trim().to_string(),
-            });
+    // with `--fix` every mismatching section of cmd.p1 gets the actual output,
+    // else the first mismatch fails the test
+    let mut fixed = false;
+    let mut mismatch = None;
+    for (name, expected, trimmed, exact_value) in [
+        ("stdout", &config.stdout, &output.stdout, &exact.stdout),
+        ("stderr", &config.stderr, &output.stderr, &exact.stderr),
+        // combined output is read as stdout
+        ("combined", &config.combined, &output.stdout, &exact.stdout),
+    ] {
+        let expected = match expected {
+            Some(e) => e,
+            None => continue,
+        };
+        let (bytes, is_exact) = match expected.read(entry) {
+            Ok(v) => v,
+            Err(e) => return err(crate::Failure::Other { io: e }),
+        };
+        let found = if is_exact { exact_value } else { trimmed };
+        if &bytes == found {
+            continue;
+        }
+        if !to_fix {
+            mismatch = Some((name, is_exact, bytes));
+            break;
         }
+        // files get the output as is, so binary output survives, sections
+        // what they are compared with
+        let found = if expected.file.is_some() {
+            exact_value
+        } else {
+            found
+        };
+        if let Err(e) = crate::fix::fix_expected(entry, name, expected, found) {
+            return err(crate::Failure::Other { io: e });
+        }
+        fixed = true;
     }
 
-    if let Some(ref stderr) = config.stderr {
-        if output.stderr != stderr.trim() {
-            return err(crate::Failure::StderrMismatch {
-                output,
-                expected: stderr.trim().to_string(),
-            });
-        }
+    if let Some((name, is_exact, expected)) = mismatch {
+        let output = if is_exact { exact } else { output };
+        return err(match name {
+            "stdout" => crate::Failure::StdoutMismatch { expected, output },
+            "stderr" => crate::Failure::StderrMismatch { expected, output },
+            _ => crate::Failure::CombinedMismatch { expected, output },
+        });
     }
 
     // if there is `output` folder we will check if `dir` is equal to `output`.
@@ -418,10 +1120,10 @@ fn test_one(
     let reference = entry.join("output");
 
     if !reference.exists() {
-        return crate::Case {
-            id,
-            result: Ok(true),
-            duration: std::time::Instant::now().duration_since(start),
+        return if fixed {
+            crate::Status::Fixed
+        } else {
+            crate::Status::Passed
         };
     }
 
@@ -432,21 +1134,45 @@ fn test_one(
 
     if to_fix {
         return match crate::dir_diff::fix(output, reference) {
-            Ok(()) => err(crate::Failure::FixMismatch),
+            Ok(()) => crate::Status::Fixed,
             Err(e) => err(crate::Failure::DirDiffError { error: e }),
         };
     }
 
-    crate::Case {
-        id: id.clone(),
-        result: match crate::dir_diff::diff(output, reference) {
-            Ok(Some(diff)) => {
-                return err(crate::Failure::OutputMismatch { diff });
-            }
-            Ok(None) => Ok(true),
-            Err(e) => return err(crate::Failure::DirDiffError { error: e }),
-        },
-        duration: std::time::Instant::now().duration_since(start),
+    match crate::dir_diff::diff(output, reference) {
+        Ok(Some(diff)) => err(crate::Failure::OutputMismatch { diff }),
+        Ok(None) => crate::Status::Passed,
+        Err(e) => err(crate::Failure::DirDiffError { error: e }),
+    }
+}
+
+fn print_trace(trace: &crate::Trace) {
+    use colored::Colorize;
+
+    println!("    {} {}", "shell:".dimmed(), trace.shell);
+    println!("    {} {}", "cmd:".dimmed(), trace.cmd);
+    println!("    {} {}", "cwd:".dimmed(), trace.cwd.to_string_lossy());
+    if trace.clear_env {
+        println!("    {} (inherited environment cleared)", "env:".dimmed());
+    }
+    for (k, v) in trace.env.iter() {
+        match v {
+            Some(v) => println!("    {} {}={}", "env:".dimmed(), k, v),
+            None => println!("    {} -{}", "env:".dimmed(), k),
+        }
+    }
+    match trace.stdin {
+        Some(ref v) => println!("    {} {}", "stdin:".dimmed(), v),
+        None => println!("    {} none", "stdin:".dimmed()),
+    }
+    if !is_test() {
+        println!(
+            "    {} copy {:?}, run {:?}, compare {:?}",
+            "timing:".dimmed(),
+            trace.copy,
+            trace.run,
+            trace.compare
+        );
     }
 }
 
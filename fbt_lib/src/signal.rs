@@ -0,0 +1,41 @@
+// signals that can be named in `signal` header, with or without `SIG` prefix,
+// numbers differ between platforms so we take them from libc
+#[cfg(unix)]
+const SIGNALS: &[(&str, i32)] = &[
+    ("SIGHUP", libc::SIGHUP),
+    ("SIGINT", libc::SIGINT),
+    ("SIGQUIT", libc::SIGQUIT),
+    ("SIGILL", libc::SIGILL),
+    ("SIGTRAP", libc::SIGTRAP),
+    ("SIGABRT", libc::SIGABRT),
+    ("SIGBUS", libc::SIGBUS),
+    ("SIGFPE", libc::SIGFPE),
+    ("SIGKILL", libc::SIGKILL),
+    ("SIGUSR1", libc::SIGUSR1),
+    ("SIGSEGV", libc::SIGSEGV),
+    ("SIGUSR2", libc::SIGUSR2),
+    ("SIGPIPE", libc::SIGPIPE),
+    ("SIGALRM", libc::SIGALRM),
+    ("SIGTERM", libc::SIGTERM),
+];
+
+// there are no signals on windows
+#[cfg(not(unix))]
+const SIGNALS: &[(&str, i32)] = &[];
+
+// `SIGTERM`, `TERM` or `15`
+pub(crate) fn signal_number(name: &str) -> Option<i32> {
+    let name = name.trim().to_uppercase();
+    if let Ok(n) = name.parse::<i32>() {
+        return Some(n).filter(|n| *n > 0);
+    }
+    SIGNALS
+        .iter()
+        .find(|(s, _)| *s == name || s.trim_start_matches("SIG") == name)
+        .map(|(_, n)| *n)
+}
+
+// `SIGTERM` for 15, `None` for signals not in `SIGNALS`
+pub(crate) fn signal_name(n: i32) -> Option<&'static str> {
+    SIGNALS.iter().find(|(_, s)| *s == n).map(|(name, _)| *name)
+}
@@ -0,0 +1,104 @@
+// boolean expression over test tags, eg `slow and not (network or docker)`.
+// `&&`/`&`, `||`/`|` and `!` can be used in place of `and`, `or` and `not`
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagExpr {
+    Tag(String),
+    Not(Box<TagExpr>),
+    And(Box<TagExpr>, Box<TagExpr>),
+    Or(Box<TagExpr>, Box<TagExpr>),
+}
+
+impl TagExpr {
+    pub fn parse(s: &str) -> Result<TagExpr, String> {
+        let tokens = tokenize(s);
+        let mut pos = 0;
+        let expr = parse_or(&tokens, &mut pos)
+            .map_err(|e| format!("invalid tag expression `{}`: {}", s, e))?;
+        match tokens.get(pos) {
+            None => Ok(expr),
+            Some(t) => Err(format!(
+                "invalid tag expression `{}`: unexpected `{}`",
+                s, t
+            )),
+        }
+    }
+
+    pub fn matches(&self, tags: &[String]) -> bool {
+        match self {
+            TagExpr::Tag(t) => tags.contains(t),
+            TagExpr::Not(e) => !e.matches(tags),
+            TagExpr::And(a, b) => a.matches(tags) && b.matches(tags),
+            TagExpr::Or(a, b) => a.matches(tags) || b.matches(tags),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' | ')' | '!' => tokens.push(c.to_string()),
+            '&' | '|' => {
+                // `&&` is same as `&`
+                if chars.peek() == Some(&c) {
+                    chars.next();
+                }
+                tokens.push(c.to_string());
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()!&|".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(word);
+            }
+        }
+    }
+    tokens
+}
+
+fn parse_or(tokens: &[String], pos: &mut usize) -> Result<TagExpr, String> {
+    let mut expr = parse_and(tokens, pos)?;
+    while matches!(tokens.get(*pos).map(String::as_str), Some("or" | "|")) {
+        *pos += 1;
+        expr = TagExpr::Or(Box::new(expr), Box::new(parse_and(tokens, pos)?));
+    }
+    Ok(expr)
+}
+
+fn parse_and(tokens: &[String], pos: &mut usize) -> Result<TagExpr, String> {
+    let mut expr = parse_not(tokens, pos)?;
+    while matches!(tokens.get(*pos).map(String::as_str), Some("and" | "&")) {
+        *pos += 1;
+        expr = TagExpr::And(Box::new(expr), Box::new(parse_not(tokens, pos)?));
+    }
+    Ok(expr)
+}
+
+fn parse_not(tokens: &[String], pos: &mut usize) -> Result<TagExpr, String> {
+    let token = match tokens.get(*pos) {
+        Some(t) => t.as_str(),
+        None => return Err("expected a tag, found end of expression".to_string()),
+    };
+    *pos += 1;
+
+    match token {
+        "not" | "!" => Ok(TagExpr::Not(Box::new(parse_not(tokens, pos)?))),
+        "(" => {
+            let expr = parse_or(tokens, pos)?;
+            if tokens.get(*pos).map(String::as_str) != Some(")") {
+                return Err("missing `)`".to_string());
+            }
+            *pos += 1;
+            Ok(expr)
+        }
+        ")" | "and" | "&" | "or" | "|" => Err(format!("expected a tag, found `{}`", token)),
+        t => Ok(TagExpr::Tag(t.to_string())),
+    }
+}
@@ -1,17 +1,70 @@
 use std::convert::TryFrom;
 
+// headers allowed in `-- fbt:` section of fbt.p1
+pub(crate) const CONFIG_HEADERS: &[&str] = &[
+    "build",
+    "cmd",
+    "exit-code",
+    "clear-env",
+    "output",
+    "allow-extension-headers",
+    "tags",
+    "watch",
+    "retries",
+    "capture-limit",
+];
+
+// headers allowed in `-- fbt:` section of cmd.p1
+pub(crate) const TEST_HEADERS: &[&str] = &[
+    "cmd",
+    "skip",
+    "exit-code",
+    "clear-env",
+    "output",
+    "tags",
+    "retries",
+    "signal",
+    "capture-limit",
+    "exact",
+    "stdout-file",
+    "stderr-file",
+    "stdin-file",
+    "stdin-cmd",
+];
+
+// headers of stdout, stderr and combined sections
+const EXPECTED_HEADERS: &[&str] = &["exact"];
+
+// bytes of stdout and of stderr kept if `capture-limit` is not set
+const DEFAULT_CAPTURE_LIMIT: usize = 16 * 1024 * 1024;
+
+// headers starting with this are allowed in both fbt.p1 and cmd.p1 if fbt.p1
+// has `allow-extension-headers: true`, so other tools can keep their data there
+pub(crate) const EXTENSION_PREFIX: &str = "x-";
+
 #[derive(Debug, Default)]
 pub(crate) struct Config {
+    // only the build of this suite, builds of parent suites are run separately
     pub build: Option<String>,
     cmd: Option<String>,
     env: Option<std::collections::HashMap<String, String>>,
     clear_env: bool,
-    output: Option<String>,
-    exit_code: Option<i32>,
+    pub output: Option<String>,
+    pub exit_code: Option<ExitCode>,
+    pub allow_extension_headers: bool,
+    // added to tags of every test in this suite
+    pub tags: Vec<String>,
+    // globs of source files, relative to the folder fbt is run from, whose
+    // change reruns all tests in watch mode
+    pub watch: Vec<String>,
+    pub retries: Option<usize>,
+    pub capture_limit: Option<usize>,
 }
 
 impl Config {
-    pub fn parse(s: &str, doc_id: &str) -> ftd::p1::Result<Config> {
+    // `parent` is the config of the enclosing suite, `Config::default()` for
+    // the top level fbt.p1, everything except `build` is inherited from it
+    pub fn parse(s: &str, doc_id: &str, parent: &Config) -> ftd::p1::Result<Config> {
         let parsed = ftd::p1::parse(s, doc_id)?;
         let mut iter = parsed.iter();
         let mut c = match iter.next() {
@@ -24,22 +77,47 @@ impl Config {
                     });
                 }
 
+                let allow_extension_headers = p1.header.bool_with_default(
+                    doc_id,
+                    p1.line_number,
+                    "allow-extension-headers",
+                    parent.allow_extension_headers,
+                )?;
+                if let Some(e) = unknown_headers(
+                    &p1.header.0,
+                    doc_id,
+                    CONFIG_HEADERS,
+                    allow_extension_headers,
+                )
+                .into_iter()
+                .next()
+                {
+                    return Err(e);
+                }
+
                 Config {
                     build: p1.header.string_optional(doc_id, p1.line_number, "build")?,
-                    cmd: p1.header.string_optional(doc_id, p1.line_number, "cmd")?,
-                    exit_code: p1
+                    cmd: p1
                         .header
-                        .i32_optional(doc_id, p1.line_number, "exit-code")?,
+                        .string_optional(doc_id, p1.line_number, "cmd")?
+                        .or_else(|| parent.cmd.clone()),
+                    exit_code: exit_code(doc_id, p1)?.or_else(|| parent.exit_code.clone()),
                     env: None,
                     clear_env: p1.header.bool_with_default(
                         doc_id,
                         p1.line_number,
                         "clear-env",
-                        false,
+                        parent.clear_env,
                     )?,
                     output: p1
                         .header
-                        .string_optional(doc_id, p1.line_number, "output")?,
+                        .string_optional(doc_id, p1.line_number, "output")?
+                        .or_else(|| parent.output.clone()),
+                    allow_extension_headers,
+                    tags: with_list(&parent.tags, doc_id, p1, "tags")?,
+                    watch: with_list(&parent.watch, doc_id, p1, "watch")?,
+                    retries: retries(doc_id, p1)?.or(parent.retries),
+                    capture_limit: capture_limit(doc_id, p1)?.or(parent.capture_limit),
                 }
             }
             None => {
@@ -73,8 +151,92 @@ impl Config {
             }
         }
 
+        c.env = match (c.env, &parent.env) {
+            (Some(v), Some(e)) => {
+                let mut e = e.clone();
+                e.extend(v);
+                Some(e)
+            }
+            (Some(v), None) => Some(v),
+            (None, v) => v.clone(),
+        };
+
         Ok(c)
     }
+
+    // config of a suite whose fbt.p1 sets nothing of its own
+    pub fn inherit(parent: &Config) -> Config {
+        Config {
+            build: None,
+            cmd: parent.cmd.clone(),
+            env: parent.env.clone(),
+            clear_env: parent.clear_env,
+            output: parent.output.clone(),
+            exit_code: parent.exit_code.clone(),
+            allow_extension_headers: parent.allow_extension_headers,
+            tags: parent.tags.clone(),
+            watch: parent.watch.clone(),
+            retries: parent.retries,
+            capture_limit: parent.capture_limit,
+        }
+    }
+}
+
+// an error for every header not in `known`, suggesting the closest known one
+pub(crate) fn unknown_headers(
+    headers: &[(usize, String, String)],
+    doc_id: &str,
+    known: &[&str],
+    allow_extensions: bool,
+) -> Vec<ftd::p1::Error> {
+    headers
+        .iter()
+        .filter(|(_, k, _)| !known.contains(&k.as_str()))
+        .filter(|(_, k, _)| !(allow_extensions && k.starts_with(EXTENSION_PREFIX)))
+        .map(|(line_number, k, _)| ftd::p1::Error::ParseError {
+            message: match suggest(k.as_str(), known) {
+                Some(s) => format!("unknown header `{}`, did you mean `{}`?", k, s),
+                None if k.starts_with(EXTENSION_PREFIX) => format!(
+                    "unknown header `{}`, set `allow-extension-headers: true` in fbt.p1 to \
+                     allow `{}` headers",
+                    k, EXTENSION_PREFIX
+                ),
+                None => format!("unknown header `{}`", k),
+            },
+            doc_id: doc_id.to_string(),
+            line_number: *line_number,
+        })
+        .collect()
+}
+
+// closest of the `known` names to `name`, if it is close enough to be a typo
+fn suggest<'a>(name: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|k| (distance(name, k), *k))
+        .filter(|(d, _)| *d <= 2)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, k)| k)
+}
+
+// levenshtein distance
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(row[j + 1])
+            };
+            prev = current;
+        }
+    }
+    row[b.len()]
 }
 
 fn read_env(
@@ -105,28 +267,216 @@ fn read_env(
     })
 }
 
-#[derive(Debug)]
+// `inherited` values followed by the ones in `key` header of `p1`, which is a
+// comma or space separated list
+fn with_list(
+    inherited: &[String],
+    doc_id: &str,
+    p1: &ftd::p1::Section,
+    key: &str,
+) -> ftd::p1::Result<Vec<String>> {
+    let mut list = inherited.to_vec();
+    if let Some(v) = p1.header.string_optional(doc_id, p1.line_number, key)? {
+        for item in v.split(|c: char| c == ',' || c.is_whitespace()) {
+            if !item.is_empty() && !list.iter().any(|t| t == item) {
+                list.push(item.to_string());
+            }
+        }
+    }
+    Ok(list)
+}
+
+fn exit_code(doc_id: &str, p1: &ftd::p1::Section) -> ftd::p1::Result<Option<ExitCode>> {
+    match p1
+        .header
+        .string_optional(doc_id, p1.line_number, "exit-code")?
+    {
+        Some(v) => match ExitCode::parse(v.as_str()) {
+            Some(e) => Ok(Some(e)),
+            None => Err(ftd::p1::Error::ParseError {
+                message: format!(
+                    "invalid exit-code `{}`, expected a number, a list like `1,2`, a range like \
+                     `64-78` or `any-nonzero`",
+                    v
+                ),
+                doc_id: doc_id.to_string(),
+                line_number: p1.line_number,
+            }),
+        },
+        None => Ok(None),
+    }
+}
+
+// how many times a failing test is run again, from `retries` header of `p1`
+fn retries(doc_id: &str, p1: &ftd::p1::Section) -> ftd::p1::Result<Option<usize>> {
+    match p1.header.i32_optional(doc_id, p1.line_number, "retries")? {
+        Some(v) if v < 0 => Err(ftd::p1::Error::ParseError {
+            message: format!("retries must not be negative, found {}", v),
+            doc_id: doc_id.to_string(),
+            line_number: p1.line_number,
+        }),
+        v => Ok(v.map(|v| v as usize)),
+    }
+}
+
+// bytes, with an optional `K`, `M` or `G` suffix, eg `64K` or `10M`
+fn capture_limit(doc_id: &str, p1: &ftd::p1::Section) -> ftd::p1::Result<Option<usize>> {
+    let v = match p1
+        .header
+        .string_optional(doc_id, p1.line_number, "capture-limit")?
+    {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let (number, unit) = match v.trim().char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&v.trim()[..i], 1024),
+        Some((i, 'M')) | Some((i, 'm')) => (&v.trim()[..i], 1024 * 1024),
+        Some((i, 'G')) | Some((i, 'g')) => (&v.trim()[..i], 1024 * 1024 * 1024),
+        _ => (v.trim(), 1),
+    };
+    match number
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+    {
+        Some(n) => Ok(Some(n)),
+        None => Err(ftd::p1::Error::ParseError {
+            message: format!(
+                "invalid capture-limit `{}`, expected bytes like `4096` or `10M`",
+                v
+            ),
+            doc_id: doc_id.to_string(),
+            line_number: p1.line_number,
+        }),
+    }
+}
+
+fn first_paragraph(body: &str) -> Option<String> {
+    let paragraph = body
+        .trim()
+        .lines()
+        .map(str::trim)
+        .take_while(|v| !v.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if paragraph.is_empty() {
+        None
+    } else {
+        Some(paragraph)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub(crate) struct TestConfig {
     pub cmd: String,
     env: Option<std::collections::HashMap<String, String>>,
-    clear_env: bool,
+    pub clear_env: bool,
     pub skip: Option<String>,
+    pub tags: Vec<String>,
     pub output: Option<String>,
-    pub stdin: Option<String>,
-    pub exit_code: i32,
-    pub stdout: Option<String>,
-    pub stderr: Option<String>,
+    pub stdin: Option<Stdin>,
+    pub exit_code: ExitCode,
+    pub stdout: Option<Expected>,
+    pub stderr: Option<Expected>,
+    // stdout and stderr together, in the order they were written
+    pub combined: Option<Expected>,
+    // a failing test is run again, from a fresh copy of input, this many times
+    pub retries: usize,
+    // the command is expected to be killed by this signal, instead of exiting
+    pub signal: Option<i32>,
+    // test fails if stdout or stderr is larger than these many bytes
+    pub capture_limit: usize,
+    // first paragraph of the prose in `-- fbt:` section
+    pub description: Option<String>,
 }
 
 impl TestConfig {
     pub fn cmd(&self) -> std::process::Command {
+        let mut cmd = self.shell(self.cmd.as_str());
+
+        if self.stdin.is_some() {
+            cmd.stdin(std::process::Stdio::piped());
+        }
+
+        cmd.stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped());
+
+        cmd
+    }
+
+    // adds a variable to the environment of the command, after `-- env:`
+    // sections so fbt provided variables can not be overridden
+    pub fn set_env(&mut self, key: &str, value: String) {
+        self.env
+            .get_or_insert_with(Default::default)
+            .insert(key.to_string(), value);
+    }
+
+    // `${VAR}` in cmd, output, stdin, file headers and env values is replaced,
+    // once all variables fbt provides for the run are set
+    pub fn interpolate(&mut self) -> Result<(), Failure> {
+        let env = self.env.clone().unwrap_or_default();
+        let vars = crate::interpolate::Vars {
+            env: &env,
+            inherit: !self.clear_env,
+        };
+        let field = |name: &str, v: &str| {
+            vars.expand(v).map_err(|message| Failure::CantInterpolate {
+                field: name.to_string(),
+                message,
+            })
+        };
+        let shell = |name: &str, v: &str| {
+            vars.expand_shell(v)
+                .map_err(|message| Failure::CantInterpolate {
+                    field: name.to_string(),
+                    message,
+                })
+        };
+
+        self.cmd = shell("cmd", self.cmd.as_str())?;
+        if let Some(ref mut o) = self.output {
+            *o = field("output", o)?;
+        }
+        match self.stdin {
+            Some(Stdin::Text(ref mut v)) => *v = field("stdin", v)?,
+            Some(Stdin::File(ref mut v)) => *v = field("stdin-file", v)?,
+            Some(Stdin::Cmd(ref mut v)) => *v = shell("stdin-cmd", v)?,
+            None => {}
+        }
+        for (name, expected) in [
+            ("stdout-file", &mut self.stdout),
+            ("stderr-file", &mut self.stderr),
+        ] {
+            if let Some(f) = expected.as_mut().and_then(|e| e.file.as_mut()) {
+                *f = field(name, f)?;
+            }
+        }
+        if let Some(ref mut e) = self.env {
+            for (k, v) in e.iter_mut() {
+                *v = vars
+                    .expand_env(k)
+                    .map_err(|message| Failure::CantInterpolate {
+                        field: format!("env {}", k),
+                        message,
+                    })?
+                    .unwrap_or_default();
+            }
+        }
+        Ok(())
+    }
+
+    // `line` run by the shell, with the environment of this test
+    pub fn shell(&self, line: &str) -> std::process::Command {
         let mut cmd = if cfg!(target_os = "windows") {
             let mut c = std::process::Command::new("cmd");
-            c.args(&["/C", self.cmd.as_str()]);
+            c.args(&["/C", line]);
             c
         } else {
             let mut c = std::process::Command::new("sh");
-            c.args(&["-c", self.cmd.as_str()]);
+            c.args(&["-c", line]);
             c
         };
 
@@ -144,19 +494,14 @@ impl TestConfig {
                 .unwrap_or_else(|_| "".into()),
         );
 
-        if self.stdin.is_some() {
-            cmd.stdin(std::process::Stdio::piped());
-        }
-
-        cmd.stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped());
-
         cmd
     }
 
     pub fn parse(s: &str, doc_id: &str, config: &Config) -> ftd::p1::Result<Self> {
         let parsed = ftd::p1::parse(s, doc_id)?;
         let mut iter = parsed.iter();
+        // default for `exact` header of stdout, stderr and combined sections
+        let exact;
         let mut c = match iter.next() {
             Some(p1) => {
                 if p1.name != "fbt" {
@@ -167,6 +512,21 @@ impl TestConfig {
                     });
                 }
 
+                exact = p1
+                    .header
+                    .bool_with_default(doc_id, p1.line_number, "exact", false)?;
+                if let Some(e) = unknown_headers(
+                    &p1.header.0,
+                    doc_id,
+                    TEST_HEADERS,
+                    config.allow_extension_headers,
+                )
+                .into_iter()
+                .next()
+                {
+                    return Err(e);
+                }
+
                 TestConfig {
                     cmd: match p1
                         .header
@@ -183,14 +543,34 @@ impl TestConfig {
                         }
                     },
                     skip: p1.header.string_optional(doc_id, p1.line_number, "skip")?,
-                    exit_code: p1
+                    tags: with_list(&config.tags, doc_id, p1, "tags")?,
+                    exit_code: exit_code(doc_id, p1)?
+                        .or_else(|| config.exit_code.clone())
+                        .unwrap_or_default(),
+                    retries: retries(doc_id, p1)?.or(config.retries).unwrap_or(0),
+                    capture_limit: capture_limit(doc_id, p1)?
+                        .or(config.capture_limit)
+                        .unwrap_or(DEFAULT_CAPTURE_LIMIT),
+                    signal: match p1
                         .header
-                        .i32_optional(doc_id, p1.line_number, "exit-code")?
-                        .or(config.exit_code)
-                        .unwrap_or(0),
-                    stdin: None,
-                    stdout: None,
-                    stderr: None,
+                        .string_optional(doc_id, p1.line_number, "signal")?
+                    {
+                        Some(v) => match crate::signal::signal_number(v.as_str()) {
+                            Some(n) => Some(n),
+                            None => {
+                                return Err(ftd::p1::Error::ParseError {
+                                    message: format!("unknown signal `{}`", v),
+                                    doc_id: doc_id.to_string(),
+                                    line_number: p1.line_number,
+                                })
+                            }
+                        },
+                        None => None,
+                    },
+                    stdin: stdin(doc_id, p1)?,
+                    stdout: expected_file(doc_id, p1, "stdout-file", exact)?,
+                    stderr: expected_file(doc_id, p1, "stderr-file", exact)?,
+                    combined: None,
                     env: config.env.clone(),
                     clear_env: p1.header.bool_with_default(
                         doc_id,
@@ -202,6 +582,7 @@ impl TestConfig {
                         .header
                         .string_optional(doc_id, p1.line_number, "output")?
                         .or_else(|| config.output.clone()),
+                    description: p1.body.as_ref().and_then(|(_, v)| first_paragraph(v)),
                 }
             }
             None => {
@@ -223,7 +604,14 @@ impl TestConfig {
                             line_number: s.line_number,
                         });
                     }
-                    c.stdin = s.body.as_ref().map(|(_, v)| v.clone());
+                    c.stdin = s.body.as_ref().map(|(_, v)| Stdin::Text(v.clone()));
+                }
+                "stdout" | "stderr" if c.combined.is_some() => {
+                    return Err(ftd::p1::Error::ParseError {
+                        message: "combined can not be used along with stdout or stderr".to_string(),
+                        doc_id: doc_id.to_string(),
+                        line_number: s.line_number,
+                    });
                 }
                 "stdout" => {
                     if c.stdout.is_some() {
@@ -233,7 +621,7 @@ impl TestConfig {
                             line_number: s.line_number,
                         });
                     }
-                    c.stdout = s.body.as_ref().map(|(_, v)| v.clone());
+                    c.stdout = expected(doc_id, s, exact)?;
                 }
                 "stderr" => {
                     if c.stderr.is_some() {
@@ -243,7 +631,25 @@ impl TestConfig {
                             line_number: s.line_number,
                         });
                     }
-                    c.stderr = s.body.as_ref().map(|(_, v)| v.clone());
+                    c.stderr = expected(doc_id, s, exact)?;
+                }
+                "combined" => {
+                    if c.combined.is_some() {
+                        return Err(ftd::p1::Error::ParseError {
+                            message: "combined provided more than once".to_string(),
+                            doc_id: doc_id.to_string(),
+                            line_number: s.line_number,
+                        });
+                    }
+                    if c.stdout.is_some() || c.stderr.is_some() {
+                        return Err(ftd::p1::Error::ParseError {
+                            message: "combined can not be used along with stdout or stderr"
+                                .to_string(),
+                            doc_id: doc_id.to_string(),
+                            line_number: s.line_number,
+                        });
+                    }
+                    c.combined = expected(doc_id, s, exact)?;
                 }
                 "env" => {
                     c.env = match (read_env(doc_id, &s.body)?, &c.env) {
@@ -270,6 +676,218 @@ impl TestConfig {
     }
 }
 
+// what the command reads from its stdin
+#[derive(Debug, Clone)]
+pub(crate) enum Stdin {
+    // `-- stdin:` section
+    Text(String),
+    // `stdin-file` header, looked up in the folder the command runs in, which
+    // has the copy of input folder, and then in the test folder
+    File(String),
+    // `stdin-cmd` header, stdout of this command, run in the same folder and
+    // with the same environment as the command, is piped to it
+    Cmd(String),
+}
+
+impl Stdin {
+    // how it is shown in verbose output
+    pub fn describe(&self) -> String {
+        match self {
+            Stdin::Text(v) => format!("{} bytes", v.len()),
+            Stdin::File(v) => format!("file {}", v),
+            Stdin::Cmd(v) => format!("output of `{}`", v),
+        }
+    }
+}
+
+fn stdin(doc_id: &str, p1: &ftd::p1::Section) -> ftd::p1::Result<Option<Stdin>> {
+    match (
+        p1.header
+            .string_optional(doc_id, p1.line_number, "stdin-file")?,
+        p1.header
+            .string_optional(doc_id, p1.line_number, "stdin-cmd")?,
+    ) {
+        (Some(_), Some(_)) => Err(ftd::p1::Error::ParseError {
+            message: "stdin-file can not be used along with stdin-cmd".to_string(),
+            doc_id: doc_id.to_string(),
+            line_number: p1.line_number,
+        }),
+        (Some(f), None) => Ok(Some(Stdin::File(f))),
+        (None, Some(c)) => Ok(Some(Stdin::Cmd(c))),
+        (None, None) => Ok(None),
+    }
+}
+
+// expected stdout, stderr or combined output
+#[derive(Debug, Clone)]
+pub(crate) struct Expected {
+    pub text: String,
+    // compared as is, instead of ignoring whitespace at either end. body of
+    // the section has one quoted string per line, so that whitespace survives
+    pub exact: bool,
+    // `stdout-file` or `stderr-file` header, a file in the test folder with the
+    // expected output
+    pub file: Option<String>,
+}
+
+impl Expected {
+    // bytes the output is compared with, `dir` is the test folder, and if
+    // they are compared exactly. a file that is not utf-8 is always compared
+    // byte for byte
+    pub fn read(&self, dir: &std::path::Path) -> std::io::Result<(Vec<u8>, bool)> {
+        let v = match self.file {
+            Some(ref f) => std::fs::read(dir.join(f))?,
+            None => self.text.as_bytes().to_vec(),
+        };
+        if self.exact || std::str::from_utf8(&v).is_err() {
+            Ok((v, true))
+        } else {
+            Ok((crate::bytes::trim(&v).to_vec(), false))
+        }
+    }
+}
+
+// `exact` is the default if the section has no `exact` header
+fn expected(doc_id: &str, s: &ftd::p1::Section, exact: bool) -> ftd::p1::Result<Option<Expected>> {
+    if let Some(e) = unknown_headers(&s.header.0, doc_id, EXPECTED_HEADERS, false)
+        .into_iter()
+        .next()
+    {
+        return Err(e);
+    }
+    let exact = s
+        .header
+        .bool_with_default(doc_id, s.line_number, "exact", exact)?;
+    let body = match s.body {
+        Some((_, ref v)) => v,
+        None => return Ok(None),
+    };
+    if !exact {
+        return Ok(Some(Expected {
+            text: body.to_string(),
+            exact,
+            file: None,
+        }));
+    }
+
+    let mut text = String::new();
+    for line in body.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        match serde_json::from_str::<String>(line) {
+            Ok(v) => text.push_str(v.as_str()),
+            Err(_) => {
+                return Err(ftd::p1::Error::ParseError {
+                    message: format!(
+                        "every line of exact {} must be a quoted string, found `{}`",
+                        s.name, line
+                    ),
+                    doc_id: doc_id.to_string(),
+                    line_number: s.line_number,
+                })
+            }
+        }
+    }
+    Ok(Some(Expected {
+        text,
+        exact,
+        file: None,
+    }))
+}
+
+// `key` is `stdout-file` or `stderr-file`, the file is compared as is if
+// `exact` is set, else with whitespace at either end of it ignored
+fn expected_file(
+    doc_id: &str,
+    p1: &ftd::p1::Section,
+    key: &str,
+    exact: bool,
+) -> ftd::p1::Result<Option<Expected>> {
+    Ok(p1
+        .header
+        .string_optional(doc_id, p1.line_number, key)?
+        .map(|f| Expected {
+            text: String::new(),
+            exact,
+            file: Some(f),
+        }))
+}
+
+// what `exit-code` header expects
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExitCode {
+    // `any-nonzero`
+    NonZero,
+    // `0`, `1,2`, `64-78` or any mix of these, every range is inclusive and a
+    // single code is a range of one
+    OneOf(Vec<(i32, i32)>),
+}
+
+impl Default for ExitCode {
+    fn default() -> Self {
+        ExitCode::OneOf(vec![(0, 0)])
+    }
+}
+
+impl ExitCode {
+    pub fn parse(s: &str) -> Option<ExitCode> {
+        let s = s.trim();
+        if s == "any-nonzero" {
+            return Some(ExitCode::NonZero);
+        }
+
+        let mut ranges = vec![];
+        for item in s.split(',').map(str::trim) {
+            if let Ok(code) = item.parse() {
+                ranges.push((code, code));
+                continue;
+            }
+            // skip the first char so a negative start is not taken as the `-`
+            let (start, end) = item
+                .char_indices()
+                .skip(1)
+                .find(|(_, c)| *c == '-')
+                .map(|(i, _)| (&item[..i], &item[i + 1..]))?;
+            match (start.trim().parse(), end.trim().parse()) {
+                (Ok(start), Ok(end)) if start <= end => ranges.push((start, end)),
+                _ => return None,
+            }
+        }
+        Some(ExitCode::OneOf(ranges))
+    }
+
+    pub fn matches(&self, code: i32) -> bool {
+        match self {
+            ExitCode::NonZero => code != 0,
+            ExitCode::OneOf(ranges) => ranges.iter().any(|(s, e)| *s <= code && code <= *e),
+        }
+    }
+}
+
+// eg `any non-zero`, `0`, `64-78` or `1, 2 or 64-78`
+impl std::fmt::Display for ExitCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ranges = match self {
+            ExitCode::NonZero => return write!(f, "any non-zero"),
+            ExitCode::OneOf(ranges) => ranges,
+        };
+        let items: Vec<_> = ranges
+            .iter()
+            .map(|(s, e)| {
+                if s == e {
+                    s.to_string()
+                } else {
+                    format!("{}-{}", s, e)
+                }
+            })
+            .collect();
+        match items.split_last() {
+            Some((last, rest)) if !rest.is_empty() => {
+                write!(f, "{} or {}", rest.join(", "), last)
+            }
+            _ => write!(f, "{}", items.join("")),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     TestsFolderMissing,
@@ -277,24 +895,288 @@ pub enum Error {
     InvalidConfig(ftd::p1::Error),
     BuildFailedToLaunch(std::io::Error),
     BuildFailed(std::process::Output),
+    CantInterpolateBuild(String),
     TestsFolderNotReadable(std::io::Error),
 }
 
 #[derive(Debug)]
 pub struct Case {
     pub id: String, // 01_basic
-    // if Ok(true) => test passed
-    // if Ok(false) => test skipped
-    // if Err(Failure) => test failed
-    pub result: Result<bool, crate::Failure>,
+    pub result: crate::Status,
     pub duration: std::time::Duration,
+    // what was executed for this case, shown with `--verbose`. None if the
+    // case never got as far as launching its command
+    pub trace: Option<crate::Trace>,
+    // set if the case was run more than once with `--repeat`
+    pub repeat: Option<Repeat>,
+    // failures of earlier attempts of a test with `retries`, result is from
+    // the last attempt
+    pub retried: Vec<crate::Failure>,
+}
+
+#[derive(Debug)]
+pub struct Repeat {
+    pub runs: usize,
+    pub passed: usize,
+    pub min: std::time::Duration,
+    pub median: std::time::Duration,
+    pub max: std::time::Duration,
+}
+
+#[derive(Debug)]
+pub enum Status {
+    Passed,
+    // test did not match the filters passed on command line, it was not run
+    Filtered,
+    // test has `skip` set in its cmd.p1
+    Skipped { reason: String },
+    // test was run with `--fix` and its output folder was updated
+    Fixed,
+    Failed(crate::Failure),
+    // test was selected, but not run as an earlier test failed in fail fast mode
+    NotRun,
+    // test was run more than once with `--repeat`, and failed only some of the
+    // times, failure is from the first run that failed
+    Flaky(crate::Failure),
+}
+
+impl Status {
+    pub fn is_failed(&self) -> bool {
+        matches!(self, Status::Failed(_) | Status::Flaky(_))
+    }
+}
+
+// which tests to run, a test must match all of these to be selected
+#[derive(Debug, Default, Clone)]
+pub struct Selection {
+    // test is selected if its id contains any of these, ignoring case
+    pub filters: Vec<String>,
+    // test is not selected if its id contains any of these, ignoring case
+    pub excludes: Vec<String>,
+    // filters and excludes must match the whole id, case sensitive
+    pub exact: bool,
+    // filters and excludes are regular expressions, searched in the id
+    pub regex: bool,
+    // test is selected if it has any of these tags
+    pub tags: Vec<String>,
+    // test is not selected if it has any of these tags
+    pub exclude_tags: Vec<String>,
+    pub tag_expr: Option<crate::TagExpr>,
+    // only tests that failed in the last run are selected
+    pub failed: bool,
+    // only tests with these ids are selected, used to rerun affected tests in
+    // watch mode
+    pub only: Option<Vec<String>>,
+    // `(k, n)`: split selected tests in `n` groups and only select the `k`th,
+    // counting from 1
+    pub shard: Option<(usize, usize)>,
+}
+
+impl Selection {
+    // error if some filter is not a valid regex when `regex` is set
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.regex {
+            return Ok(());
+        }
+        for f in self.filters.iter().chain(self.excludes.iter()) {
+            if let Err(e) = regex::Regex::new(f.as_str()) {
+                return Err(format!("invalid regex `{}`: {}", f, e));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn matches_id(&self, id: &str) -> bool {
+        (self.filters.is_empty() || self.filters.iter().any(|v| self.id_matches(v, id)))
+            && !self.excludes.iter().any(|v| self.id_matches(v, id))
+            && self
+                .only
+                .as_ref()
+                .map(|ids| ids.iter().any(|v| v == id))
+                .unwrap_or(true)
+    }
+
+    fn id_matches(&self, pattern: &str, id: &str) -> bool {
+        if self.exact {
+            if self.regex {
+                return regex::Regex::new(format!("^(?:{})$", pattern).as_str())
+                    .map(|r| r.is_match(id))
+                    .unwrap_or(false);
+            }
+            return pattern == id;
+        }
+        if self.regex {
+            return regex::Regex::new(pattern)
+                .map(|r| r.is_match(id))
+                .unwrap_or(false);
+        }
+        id.to_lowercase().contains(&pattern.to_lowercase())
+    }
+
+    pub(crate) fn matches_tags(&self, tags: &[String]) -> bool {
+        (self.tags.is_empty() || self.tags.iter().any(|t| tags.contains(t)))
+            && !self.exclude_tags.iter().any(|t| tags.contains(t))
+            && self
+                .tag_expr
+                .as_ref()
+                .map(|e| e.matches(tags))
+                .unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    // overwrite the expected `output` folders, and expected stdout, stderr and
+    // combined output, with actual output
+    pub to_fix: bool,
+    // stop at the first failing test, rest are reported as not run
+    pub fail_fast: bool,
+    // run every test this many times, to find flaky ones, 0 is same as 1
+    pub repeat: usize,
+    // stop at the first test that passes some times and fails other times
+    pub stop_on_flaky: bool,
+    // run tests that failed in the last run before the rest
+    pub failed_first: bool,
+    // also list tests that were not selected
+    pub show_filtered: bool,
+    // show command, environment and timings of each test
+    pub verbose: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct LastRun {
+    pub tests: std::collections::BTreeMap<String, LastResult>,
+}
+
+impl LastRun {
+    pub fn failed(&self, id: &str) -> bool {
+        self.tests
+            .get(id)
+            .map(|t| matches!(t.result, LastStatus::Failed | LastStatus::Flaky))
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Debug)]
+pub struct LastResult {
+    pub result: LastStatus,
+    // not known for tests only ever run as part of a shard
+    pub duration: Option<std::time::Duration>,
+}
+
+// `flaky` is also a test that passed only after a retry
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LastStatus {
+    Passed,
+    Skipped,
+    Fixed,
+    Failed,
+    Flaky,
+}
+
+// a folder of tests along with its fbt.p1, the tests folder itself or a folder
+// inside it that has fbt.p1 but no cmd.p1
+#[derive(Debug)]
+pub(crate) struct Suite {
+    pub folder: std::path::PathBuf,
+    pub config: Config,
+    pub parent: Option<std::rc::Rc<Suite>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct TestDir {
+    pub dir: std::path::PathBuf,
+    // path of the test folder relative to tests folder, eg `cli/init/01_basic`
+    pub id: String,
+    pub suite: std::rc::Rc<Suite>,
+}
+
+#[derive(Debug)]
+pub struct Problem {
+    pub file: String,
+    // 0 if the problem is not about any particular line
+    pub line_number: usize,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct ListedTest {
+    pub id: String,
+    // Err if cmd.p1 of this test could not be read or parsed
+    pub result: Result<crate::TestInfo, crate::Failure>,
+}
+
+#[derive(Debug)]
+pub struct TestInfo {
+    pub cmd: String,
+    pub skip: Option<String>,
+    pub tags: Vec<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Trace {
+    pub shell: String,
+    pub cmd: String,
+    pub cwd: std::path::PathBuf,
+    pub clear_env: bool,
+    // variables that differ from the environment fbt itself was launched with,
+    // None means the variable was removed
+    pub env: Vec<(String, Option<String>)>,
+    pub stdin: Option<String>,
+    pub copy: std::time::Duration,
+    pub run: std::time::Duration,
+    pub compare: std::time::Duration,
+}
+
+impl Trace {
+    pub(crate) fn new(
+        config: &TestConfig,
+        cmd: &std::process::Command,
+        cwd: &std::path::Path,
+        copy: std::time::Duration,
+    ) -> Self {
+        let mut shell = vec![cmd.get_program().to_string_lossy().to_string()];
+        // last argument is the command itself
+        let args: Vec<_> = cmd.get_args().collect();
+        if let Some((_, rest)) = args.split_last() {
+            shell.extend(rest.iter().map(|v| v.to_string_lossy().to_string()));
+        }
+
+        let mut env: Vec<_> = cmd
+            .get_envs()
+            .filter(|(k, v)| config.clear_env || std::env::var_os(k).as_deref() != *v)
+            .map(|(k, v)| {
+                (
+                    k.to_string_lossy().to_string(),
+                    v.map(|v| v.to_string_lossy().to_string()),
+                )
+            })
+            .collect();
+        env.sort();
+
+        Trace {
+            shell: shell.join(" "),
+            cmd: config.cmd.clone(),
+            cwd: cwd.to_path_buf(),
+            clear_env: config.clear_env,
+            env,
+            stdin: config.stdin.as_ref().map(Stdin::describe),
+            copy,
+            run: Default::default(),
+            compare: Default::default(),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Output {
-    pub exit_code: i32,
-    pub stdout: String,
-    pub stderr: String,
+    // `None` if the command was killed by a signal
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
 }
 
 impl Output {
@@ -307,45 +1189,66 @@ impl Output {
         // both of them are the same folder, and we see the former path, but the lauched processes see the later
 
         let private_v = format!("/private{}", v.as_str());
-        self.stdout = self.stdout.replace(private_v.as_str(), "<cwd>");
-        self.stderr = self.stderr.replace(private_v.as_str(), "<cwd>");
-        self.stdout = self.stdout.replace(v.as_str(), "<cwd>");
-        self.stderr = self.stderr.replace(v.as_str(), "<cwd>");
+        for from in [private_v.as_str(), v.as_str()] {
+            self.stdout = crate::bytes::replace(&self.stdout, from, "<cwd>");
+            self.stderr = crate::bytes::replace(&self.stderr, from, "<cwd>");
+        }
 
         self
     }
+
+    // how the command ended, eg `exit code 1`, `signal SIGSEGV` or `signal 31`
+    pub fn status(&self) -> String {
+        match (self.exit_code, self.signal) {
+            (_, Some(s)) => match crate::signal::signal_name(s) {
+                Some(name) => format!("signal {}", name),
+                None => format!("signal {}", s),
+            },
+            (Some(c), None) => format!("exit code {}", c),
+            (None, None) => "unknown status".to_string(),
+        }
+    }
 }
 
 impl TryFrom<&std::process::Output> for Output {
     type Error = &'static str;
 
     fn try_from(o: &std::process::Output) -> std::result::Result<Self, Self::Error> {
+        Output::new(o, true)
+    }
+}
+
+impl Output {
+    // `trim` removes whitespace at either end of stdout and stderr
+    pub fn new(o: &std::process::Output, trim: bool) -> Result<Output, &'static str> {
+        let text = |v: &[u8]| {
+            if trim {
+                crate::bytes::trim(v).to_vec()
+            } else {
+                v.to_vec()
+            }
+        };
+
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(&o.status);
+        #[cfg(not(unix))]
+        let signal = None;
+
+        if o.status.code().is_none() && signal.is_none() {
+            return Err("cant read exit_code");
+        }
+
         Ok(Output {
-            exit_code: match o.status.code() {
-                Some(code) => code,
-                None => return Err("cant read exit_code"),
-            },
-            stdout: {
-                std::str::from_utf8(&o.stdout)
-                    .unwrap_or("")
-                    .trim()
-                    .to_string()
-            },
-            stderr: {
-                std::str::from_utf8(&o.stderr)
-                    .unwrap_or("")
-                    .trim()
-                    .to_string()
-            },
+            exit_code: o.status.code(),
+            signal,
+            stdout: text(&o.stdout),
+            stderr: text(&o.stderr),
         })
     }
 }
 
 #[derive(Debug)]
 pub enum Failure {
-    Skipped {
-        reason: String,
-    },
     CmdFileMissing,
     CmdFileInvalid {
         error: ftd::p1::Error,
@@ -362,19 +1265,45 @@ pub enum Failure {
         reason: &'static str,
     },
     UnexpectedStatusCode {
-        expected: i32,
+        expected: ExitCode,
+        output: Output,
+    },
+    // killed by a signal other than the one in `signal` header, or by any
+    // signal if there is none, or exited normally when a signal was expected
+    UnexpectedSignal {
+        expected: Option<i32>,
         output: Output,
     },
     CantReadOutput {
         output: std::process::Output,
         reason: &'static str,
     },
+    // stdout or stderr was larger than `capture-limit`
+    CaptureOverflow {
+        stream: &'static str,
+        limit: usize,
+    },
+    // `${VAR}` in `field` could not be replaced
+    CantInterpolate {
+        field: String,
+        message: String,
+    },
+    // `stdin-cmd` did not exit with 0
+    StdinCmdFailed {
+        status: std::process::ExitStatus,
+        stderr: Vec<u8>,
+    },
     StdoutMismatch {
-        expected: String,
+        expected: Vec<u8>,
         output: Output,
     },
     StderrMismatch {
-        expected: String,
+        expected: Vec<u8>,
+        output: Output,
+    },
+    // `stdout` of output has both stdout and stderr
+    CombinedMismatch {
+        expected: Vec<u8>,
         output: Output,
     },
     DirDiffError {
@@ -383,5 +1312,6 @@ pub enum Failure {
     OutputMismatch {
         diff: crate::DirDiff,
     },
-    FixMismatch,
+    // with `--repeat`, a run passed but only after a retry
+    PassedAfterRetry,
 }
@@ -0,0 +1,500 @@
+// `fbt --watch` runs the tests, waits for a change in the tests folder or in
+// files matching `watch` globs of fbt.p1, and runs them again. a change inside
+// a test's folder only reruns that test, any other change reruns all of them
+
+// how long to wait for more changes after one, as editors and builds usually
+// touch many files at once
+const DEBOUNCE: std::time::Duration = std::time::Duration::from_millis(200);
+
+pub fn main_watch(
+    selection: &crate::Selection,
+    options: &crate::RunOptions,
+    folder: Option<String>,
+) -> Option<i32> {
+    use colored::Colorize;
+
+    let test_folder = crate::run::test_folder(folder);
+    let watched = watch(test_folder.as_str(), |only| {
+        // clear the screen and move cursor to top left
+        print!("\x1b[2J\x1b[H");
+        let selection = crate::Selection {
+            only,
+            ..selection.clone()
+        };
+        crate::main_with_filters(&selection, options, Some(test_folder.clone()));
+        println!("{}", "Watching for changes, press Ctrl-C to stop".yellow());
+        true
+    });
+
+    match watched {
+        Ok(()) => None,
+        Err(e) => {
+            eprintln!("{}", format!("Cant watch for changes: {:?}", e).red());
+            Some(1)
+        }
+    }
+}
+
+// calls `run` with the ids of tests to run, `None` for all of them, and again
+// after every change till it returns false. files are watched from before the
+// first run, so changes made while tests are running are not missed
+fn watch(
+    test_folder: &str,
+    mut run: impl FnMut(Option<Vec<String>>) -> bool,
+) -> std::io::Result<()> {
+    // if fbt.p1 can not be read only the tests folder is watched, so the
+    // run after fixing it picks up its globs again
+    let mut globs = watch_globs(test_folder);
+    let mut watcher = Watcher::new(&roots(test_folder, &globs))?;
+    // every run writes its results there, which is not a change to react to
+    let results = crate::last_run::path(test_folder)
+        .parent()
+        .map(segments)
+        .unwrap_or_default();
+
+    let mut only = None;
+    while run(only.take()) {
+        let changed = watcher.wait(&|p| {
+            !segments(p).starts_with(results.as_slice())
+                && (p.starts_with(test_folder) || globs.iter().any(|g| glob_match(g, p)))
+        })?;
+
+        let now = watch_globs(test_folder);
+        if now != globs {
+            globs = now;
+            watcher = Watcher::new(&roots(test_folder, &globs))?;
+        }
+        only = affected(test_folder, &changed);
+    }
+    Ok(())
+}
+
+// `watch` globs of all suites
+fn watch_globs(test_folder: &str) -> Vec<String> {
+    let mut globs: Vec<String> = vec![];
+    if let Ok((root, tests)) = crate::run::discover(test_folder) {
+        for config in std::iter::once(&root.config).chain(tests.iter().map(|t| &t.suite.config)) {
+            for g in config.watch.iter() {
+                if !globs.contains(g) {
+                    globs.push(g.to_string());
+                }
+            }
+        }
+    }
+    globs
+}
+
+// ids of tests whose folders have `changed`, `None` if all tests have to run
+fn affected(test_folder: &str, changed: &[std::path::PathBuf]) -> Option<Vec<String>> {
+    let (_, tests) = crate::run::discover(test_folder).ok()?;
+    let ids: Vec<&str> = tests.iter().map(|t| t.id.as_str()).collect();
+    affected_ids(test_folder, &ids, changed)
+}
+
+// paths are compared component by component, ignoring any `.`, so `01_a` is
+// not affected by a change in `01_ab`
+fn affected_ids(
+    test_folder: &str,
+    tests: &[&str],
+    changed: &[std::path::PathBuf],
+) -> Option<Vec<String>> {
+    let folder = segments(std::path::Path::new(test_folder));
+    let mut ids: Vec<String> = vec![];
+    for path in changed.iter() {
+        let path = segments(path);
+        let test = path.strip_prefix(folder.as_slice()).and_then(|rel| {
+            tests.iter().find(|id| {
+                let id = segments(std::path::Path::new(id));
+                rel.starts_with(id.as_slice())
+            })
+        });
+        match test {
+            Some(t) if !ids.iter().any(|i| i == t) => ids.push(t.to_string()),
+            Some(_) => {}
+            // a source file or fbt.p1 of some suite
+            None => return None,
+        }
+    }
+    Some(ids)
+}
+
+// folders to watch, along with how deep inside them changes matter, `None`
+// meaning all the way down
+fn roots(test_folder: &str, globs: &[String]) -> Vec<(std::path::PathBuf, Option<usize>)> {
+    let mut roots = vec![(std::path::PathBuf::from(test_folder), None)];
+    for g in globs.iter() {
+        let segments = segments(std::path::Path::new(g.as_str()));
+        let literal = segments
+            .iter()
+            .take_while(|s| !s.contains(['*', '?']))
+            .count();
+        let (dir, rest) = if literal == segments.len() {
+            // a plain file, its folder is watched
+            segments.split_at(literal.saturating_sub(1))
+        } else {
+            segments.split_at(literal)
+        };
+        let dir = if dir.is_empty() {
+            ".".to_string()
+        } else {
+            dir.join("/")
+        };
+        let depth = if rest.iter().any(|s| s == "**") {
+            None
+        } else {
+            Some(rest.len())
+        };
+        roots.push((std::path::PathBuf::from(dir), depth));
+    }
+    roots
+}
+
+// components of `path` without any `.`
+fn segments(path: &std::path::Path) -> Vec<String> {
+    path.components()
+        .filter(|c| !matches!(c, std::path::Component::CurDir))
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect()
+}
+
+// `*` and `?` match within one component of the path, `**` matches any
+// number of components
+fn glob_match(glob: &str, path: &std::path::Path) -> bool {
+    fn components(glob: &[String], path: &[String]) -> bool {
+        match glob.split_first() {
+            None => path.is_empty(),
+            Some((g, rest)) if g == "**" => (0..=path.len()).any(|i| components(rest, &path[i..])),
+            Some((g, rest)) => match path.split_first() {
+                Some((p, path)) => {
+                    let g: Vec<char> = g.chars().collect();
+                    let p: Vec<char> = p.chars().collect();
+                    chars(&g, &p) && components(rest, path)
+                }
+                None => false,
+            },
+        }
+    }
+
+    fn chars(glob: &[char], s: &[char]) -> bool {
+        match glob.split_first() {
+            None => s.is_empty(),
+            Some(('*', rest)) => (0..=s.len()).any(|i| chars(rest, &s[i..])),
+            Some(('?', rest)) => !s.is_empty() && chars(rest, &s[1..]),
+            Some((c, rest)) => s.first() == Some(c) && chars(rest, &s[1..]),
+        }
+    }
+
+    components(&segments(std::path::Path::new(glob)), &segments(path))
+}
+
+#[cfg(target_os = "linux")]
+struct Watcher {
+    inotify: std::fs::File,
+    // watch descriptor to the folder it watches
+    dirs: std::collections::HashMap<i32, std::path::PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    fn new(roots: &[(std::path::PathBuf, Option<usize>)]) -> std::io::Result<Watcher> {
+        use std::os::unix::io::FromRawFd;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // so the descriptor gets closed along with the watcher
+        let inotify = unsafe { std::fs::File::from_raw_fd(fd) };
+
+        let mut dirs = std::collections::HashMap::new();
+        for (root, depth) in roots.iter() {
+            let mut walk = walkdir::WalkDir::new(root);
+            if let Some(depth) = depth {
+                walk = walk.max_depth(depth.saturating_sub(1));
+            }
+            for entry in walk
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|e| e.file_type().is_dir())
+            {
+                add_watch(fd, &mut dirs, entry.path());
+            }
+        }
+        Ok(Watcher { inotify, dirs })
+    }
+
+    // blocks till some file for which `relevant` is true changes, and returns
+    // all such files changed since the last call, till things settle down
+    fn wait(
+        &mut self,
+        relevant: &dyn Fn(&std::path::Path) -> bool,
+    ) -> std::io::Result<Vec<std::path::PathBuf>> {
+        use std::io::Read;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::io::AsRawFd;
+
+        let fd = self.inotify.as_raw_fd();
+        let mut changed = vec![];
+        let mut buf = [0u8; 4096];
+        loop {
+            let mut poll = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout = if changed.is_empty() {
+                -1
+            } else {
+                DEBOUNCE.as_millis() as i32
+            };
+            match unsafe { libc::poll(&mut poll, 1, timeout) } {
+                0 => return Ok(changed),
+                n if n < 0 => {
+                    let e = std::io::Error::last_os_error();
+                    if e.kind() == std::io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                }
+                _ => {}
+            }
+
+            // buffer holds a list of `struct inotify_event`, each followed by
+            // `len` bytes of nul padded file name
+            let len = self.inotify.read(&mut buf)?;
+            let mut offset = 0;
+            while offset + 16 <= len {
+                let int = |i: usize| {
+                    let mut b = [0u8; 4];
+                    b.copy_from_slice(&buf[offset + i..offset + i + 4]);
+                    b
+                };
+                let wd = i32::from_ne_bytes(int(0));
+                let mask = u32::from_ne_bytes(int(4));
+                let name_len = u32::from_ne_bytes(int(12)) as usize;
+                let name = &buf[offset + 16..(offset + 16 + name_len).min(len)];
+                let name = name.split(|b| *b == 0).next().unwrap_or_default();
+                offset += 16 + name_len;
+
+                let path = match self.dirs.get(&wd) {
+                    Some(dir) if name.is_empty() => dir.to_path_buf(),
+                    Some(dir) => dir.join(std::ffi::OsStr::from_bytes(name)),
+                    None => continue,
+                };
+                if mask & libc::IN_ISDIR != 0 && mask & libc::IN_CREATE != 0 {
+                    add_watch(fd, &mut self.dirs, &path);
+                }
+                if relevant(&path) && !changed.contains(&path) {
+                    changed.push(path);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn add_watch(
+    fd: i32,
+    dirs: &mut std::collections::HashMap<i32, std::path::PathBuf>,
+    dir: &std::path::Path,
+) {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = match std::ffi::CString::new(dir.as_os_str().as_bytes()) {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+    let mask = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MODIFY
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO;
+    // folders we cant watch, eg as they got deleted meanwhile, are ignored
+    let wd = unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) };
+    if wd >= 0 {
+        dirs.insert(wd, dir.to_path_buf());
+    }
+}
+
+// no inotify here, so we look at modification times of all files every now
+// and then
+#[cfg(not(target_os = "linux"))]
+struct Watcher {
+    roots: Vec<(std::path::PathBuf, Option<usize>)>,
+    // modification times as of the last call to `wait`
+    before: std::collections::HashMap<std::path::PathBuf, std::time::SystemTime>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    fn new(roots: &[(std::path::PathBuf, Option<usize>)]) -> std::io::Result<Watcher> {
+        let roots = roots.to_vec();
+        Ok(Watcher {
+            before: snapshot(&roots),
+            roots,
+        })
+    }
+
+    // blocks till some file for which `relevant` is true changes, and returns
+    // all such files changed since the last call, till things settle down
+    fn wait(
+        &mut self,
+        relevant: &dyn Fn(&std::path::Path) -> bool,
+    ) -> std::io::Result<Vec<std::path::PathBuf>> {
+        let mut changed = vec![];
+        loop {
+            let now = snapshot(&self.roots);
+            let mut new: Vec<_> = now
+                .iter()
+                .filter(|(path, modified)| self.before.get(*path) != Some(*modified))
+                .map(|(path, _)| path.to_path_buf())
+                .chain(
+                    self.before
+                        .keys()
+                        .filter(|p| !now.contains_key(*p))
+                        .cloned(),
+                )
+                .filter(|p| relevant(p) && !changed.contains(p))
+                .collect();
+            self.before = now;
+            if new.is_empty() && !changed.is_empty() {
+                return Ok(changed);
+            }
+            changed.append(&mut new);
+            std::thread::sleep(DEBOUNCE);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn snapshot(
+    roots: &[(std::path::PathBuf, Option<usize>)],
+) -> std::collections::HashMap<std::path::PathBuf, std::time::SystemTime> {
+    let mut files = std::collections::HashMap::new();
+    for (root, depth) in roots.iter() {
+        let mut walk = walkdir::WalkDir::new(root);
+        if let Some(depth) = depth {
+            walk = walk.max_depth(*depth);
+        }
+        for entry in walk.into_iter().filter_map(Result::ok) {
+            if let Some(modified) = entry.metadata().ok().and_then(|m| m.modified().ok()) {
+                files.insert(entry.path().to_path_buf(), modified);
+            }
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    fn paths(v: &[&str]) -> Vec<std::path::PathBuf> {
+        v.iter().map(std::path::PathBuf::from).collect()
+    }
+
+    #[test]
+    fn glob_match() {
+        let m = |g: &str, p: &str| super::glob_match(g, std::path::Path::new(p));
+
+        assert!(m("src/*.rs", "src/main.rs"));
+        assert!(m("./src/*.rs", "src/main.rs"));
+        assert!(m("src/*.rs", "./src/main.rs"));
+        assert!(!m("src/*.rs", "src/bin/main.rs"));
+        assert!(m("src/?.rs", "src/a.rs"));
+        assert!(!m("src/?.rs", "src/ab.rs"));
+
+        // `**` matches zero or more components
+        assert!(m("src/**/*.rs", "src/main.rs"));
+        assert!(m("src/**/*.rs", "src/a/b/main.rs"));
+        assert!(m("**", "anything/at/all"));
+        assert!(m("src/**", "src"));
+        assert!(!m("src/**/*.rs", "lib/main.rs"));
+
+        // `*` does not cross a `/`, and a prefix is not a match
+        assert!(!m("src*", "src/main.rs"));
+        assert!(!m("src", "src/main.rs"));
+        assert!(!m("src/main.rs", "src"));
+    }
+
+    #[test]
+    fn roots() {
+        let roots = super::roots(
+            "./tests",
+            &[
+                "Cargo.toml".to_string(),
+                "src/*.rs".to_string(),
+                "./lib/**/*.rs".to_string(),
+                "*.md".to_string(),
+            ],
+        );
+        assert_eq!(
+            roots,
+            vec![
+                (std::path::PathBuf::from("./tests"), None),
+                // a plain file in the current folder
+                (std::path::PathBuf::from("."), Some(1)),
+                (std::path::PathBuf::from("src"), Some(1)),
+                (std::path::PathBuf::from("lib"), None),
+                (std::path::PathBuf::from("."), Some(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn affected() {
+        let tests = ["01_a", "01_ab", "cli/init/01_basic"];
+        let a =
+            |folder: &str, changed: &[&str]| super::affected_ids(folder, &tests, &paths(changed));
+
+        assert_eq!(
+            a("./tests", &["./tests/01_a/cmd.p1"]),
+            Some(vec!["01_a".to_string()])
+        );
+        // `.` in either path does not matter
+        assert_eq!(
+            a("./tests", &["tests/01_ab/input/x"]),
+            Some(vec!["01_ab".to_string()])
+        );
+        assert_eq!(
+            a("tests", &["./tests/cli/init/01_basic/cmd.p1", "tests/01_a"]),
+            Some(vec!["cli/init/01_basic".to_string(), "01_a".to_string()])
+        );
+        // same test changed twice is listed once
+        assert_eq!(
+            a("tests", &["tests/01_a/x", "tests/01_a/y"]),
+            Some(vec!["01_a".to_string()])
+        );
+        // fbt.p1 of a suite, a folder that is not a test, or anything
+        // outside the tests folder reruns everything
+        assert_eq!(a("tests", &["tests/cli/fbt.p1"]), None);
+        assert_eq!(a("tests", &["tests/01_abc/cmd.p1"]), None);
+        assert_eq!(a("tests", &["tests-old/01_a/cmd.p1"]), None);
+        assert_eq!(a("tests", &["src/main.rs", "tests/01_a/x"]), None);
+        assert_eq!(a("tests", &[]), Some(vec![]));
+    }
+
+    #[test]
+    fn watch() {
+        let folder = std::env::temp_dir().join(format!("fbt-watch-{}", std::process::id()));
+        let tests = folder.join("tests");
+        for id in ["01_a", "02_b"] {
+            std::fs::create_dir_all(tests.join(id)).unwrap();
+            std::fs::write(tests.join(id).join("cmd.p1"), "-- fbt:\ncmd: true\n").unwrap();
+        }
+
+        // a change made while the tests run, before waiting for changes
+        // starts, still gets the changed test run again
+        let mut runs = vec![];
+        let watched = super::watch(tests.to_string_lossy().as_ref(), |only| {
+            if runs.is_empty() {
+                std::fs::write(tests.join("01_a/cmd.p1"), "-- fbt:\ncmd: false\n").unwrap();
+            }
+            runs.push(only);
+            runs.len() < 2
+        });
+        let _ = std::fs::remove_dir_all(&folder);
+
+        assert!(watched.is_ok());
+        assert_eq!(runs, vec![None, Some(vec!["01_a".to_string()])]);
+    }
+}